@@ -1,96 +1,446 @@
 //! This aligner implementation is based on [WhisperTimeSync](https://github.com/EtienneAb3d/WhisperTimeSync) which itself seems to be based on a variation of the [Needleman-Wunsch](https://en.wikipedia.org/wiki/Needleman%E2%80%93Wunsch_algorithm) sequence alignment algorithm.
 
-/// [Needleman-Wunsch](https://en.wikipedia.org/wiki/Needleman%E2%80%93Wunsch_algorithm) sequence
-/// alignment algorithm but minimizing cost instead of maximizing score and with distinct gap costs
-/// for items.
-pub fn align(
-    a: usize,
-    b: usize,
-    mut gap_cost_for: impl FnMut(bool, usize) -> f64,
-    mut pairwise_cost: impl FnMut(usize, usize) -> f64,
-) -> Vec<(Option<usize>, Option<usize>)> {
-    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-    enum Choice {
-        Match,
-        A,
-        B,
-    }
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+type Pair = (Option<usize>, Option<usize>);
+/// A [`Pair`] together with its cost.
+type CostedPair = (Option<usize>, Option<usize>, f64);
 
-    let mut choices = vec![vec![Choice::A; b + 1]; a + 1];
-    let mut costs = vec![vec![0.0; b + 1]; a + 1];
+/// Above this many cells the full matrix takes more than a gigabyte of memory.
+const LINEAR_ALIGNMENT_CELLS: usize = 1 << 27;
 
-    for i in 1..=a {
-        choices[i][0] = Choice::A;
-        costs[i][0] = costs[i - 1][0] + gap_cost_for(false, i - 1);
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Choice {
+    Match,
+    A,
+    B,
+}
+
+/// Everything needed to compute the cost of a cell of the alignment matrix.
+struct Costs<P> {
+    /// Cost of leaving each item of `a` unmatched.
+    a_gaps: Vec<f64>,
+    /// Cost of leaving each item of `b` unmatched.
+    b_gaps: Vec<f64>,
+    pairwise_cost: P,
+}
+
+impl<P: FnMut(usize, usize) -> f64> Costs<P> {
+    /// Returns the costs together with the first column and row of the alignment matrix.
+    ///
+    /// Gap costs are taken as differences of the accumulated ones so that every variant of the
+    /// algorithm computes exactly the same floating point values.
+    fn new(
+        a: usize,
+        b: usize,
+        mut gap_cost_for: impl FnMut(bool, usize) -> f64,
+        pairwise_cost: P,
+    ) -> (Self, Vec<f64>, Vec<f64>) {
+        let mut left = vec![0.0; a + 1];
+        for i in 1..=a {
+            left[i] = left[i - 1] + gap_cost_for(false, i - 1);
+        }
+
+        let mut top = vec![0.0; b + 1];
+        for j in 1..=b {
+            top[j] = top[j - 1] + gap_cost_for(true, j - 1);
+        }
+
+        let costs = Costs {
+            a_gaps: left.windows(2).map(|w| w[1] - w[0]).collect(),
+            b_gaps: top.windows(2).map(|w| w[1] - w[0]).collect(),
+            pairwise_cost,
+        };
+        (costs, left, top)
     }
 
-    for j in 1..=b {
-        choices[0][j] = Choice::B;
-        costs[0][j] = costs[0][j - 1] + gap_cost_for(true, j - 1);
+    /// Cost and choice for cell `(i, j)` given the costs of the cells diagonally before, above and
+    /// to the left of it.
+    fn step(&mut self, i: usize, j: usize, diagonal: f64, up: f64, left: f64) -> (f64, Choice) {
+        let match_cost = diagonal + (self.pairwise_cost)(i - 1, j - 1);
+        let delete_cost = up + self.a_gaps[i - 1];
+        let insert_cost = left + self.b_gaps[j - 1];
+
+        if match_cost <= delete_cost && match_cost <= insert_cost {
+            (match_cost, Choice::Match)
+        } else if delete_cost < insert_cost {
+            (delete_cost, Choice::A)
+        } else {
+            (insert_cost, Choice::B)
+        }
     }
 
-    for i in 1..=a {
-        for j in 1..=b {
-            let match_cost = costs[i - 1][j - 1] + pairwise_cost(i - 1, j - 1);
-            let delete_cost = costs[i - 1][j] + (costs[i][0] - costs[i - 1][0]);
-            let insert_cost = costs[i][j - 1] + (costs[0][j] - costs[0][j - 1]);
-
-            // println!(
-            //     "match {} delete {} insert {}",
-            //     match_cost, delete_cost, insert_cost
-            // );
-
-            if match_cost <= delete_cost && match_cost <= insert_cost {
-                choices[i][j] = Choice::Match;
-                costs[i][j] = match_cost;
-            } else if delete_cost < insert_cost {
-                choices[i][j] = Choice::A;
-                costs[i][j] = delete_cost;
-            } else {
-                choices[i][j] = Choice::B;
-                costs[i][j] = insert_cost;
+    /// Aligns the region of the matrix starting at cell `(r0, c0)` whose first row and column have
+    /// the costs `top` and `left`, by filling in the whole region.
+    ///
+    /// Pairs are pushed onto `out` in reverse order.
+    fn align_region_full(
+        &mut self,
+        r0: usize,
+        c0: usize,
+        top: &[f64],
+        left: &[f64],
+        out: &mut Vec<(Option<usize>, Option<usize>)>,
+    ) {
+        let height = left.len() - 1;
+        let width = top.len() - 1;
+
+        let mut choices = vec![vec![Choice::A; width + 1]; height + 1];
+        let mut costs = vec![vec![0.0; width + 1]; height + 1];
+
+        for (row, &cost) in costs.iter_mut().zip(left) {
+            row[0] = cost;
+        }
+
+        for j in 1..=width {
+            choices[0][j] = Choice::B;
+            costs[0][j] = top[j];
+        }
+
+        for i in 1..=height {
+            for j in 1..=width {
+                (costs[i][j], choices[i][j]) = self.step(
+                    r0 + i,
+                    c0 + j,
+                    costs[i - 1][j - 1],
+                    costs[i - 1][j],
+                    costs[i][j - 1],
+                );
             }
+        }
 
-            // println!("score[{i}][{j}] = {}", costs[i][j]);
-            // println!("choices[{i}][{j}] = {:?}", choices[i][j]);
+        let mut i = height;
+        let mut j = width;
+        while i > 0 || j > 0 {
+            out.push(match choices[i][j] {
+                Choice::Match => {
+                    i -= 1;
+                    j -= 1;
+                    (Some(r0 + i), Some(c0 + j))
+                }
+                Choice::A => {
+                    i -= 1;
+                    (Some(r0 + i), None)
+                }
+                Choice::B => {
+                    j -= 1;
+                    (None, Some(c0 + j))
+                }
+            })
         }
     }
 
-    // for row in costs.iter() {
-    //     for e in row {
-    //         print!("{:>7} ", format!("{e:.3}"));
-    //     }
-    //     println!();
-    // }
+    /// Same as [`Costs::align_region_full`] but only keeping a few rows of the matrix in memory.
+    ///
+    /// The region is split at its middle row. A first pass finds the cell of that row through which
+    /// the optimal path leaves it by tracking, for every cell below it, where its own path leaves the
+    /// middle row. The region is then split into the part before and after that cell, whose first
+    /// row and column hold the same costs as in the full matrix, so both parts take exactly the same
+    /// path as [`Costs::align_region_full`] would.
+    fn align_region_linear(
+        &mut self,
+        r0: usize,
+        c0: usize,
+        top: &[f64],
+        left: &[f64],
+        out: &mut Vec<(Option<usize>, Option<usize>)>,
+    ) {
+        const FULL_REGION_CELLS: usize = 1 << 16;
 
-    let mut alignment = vec![];
+        let height = left.len() - 1;
+        let width = top.len() - 1;
+        if height < 2 || (height + 1) * (width + 1) <= FULL_REGION_CELLS {
+            return self.align_region_full(r0, c0, top, left, out);
+        }
 
-    let mut i = a;
-    let mut j = b;
-    while i > 0 || j > 0 {
-        alignment.push(match choices[i][j] {
-            Choice::Match => {
-                i -= 1;
-                j -= 1;
-                (Some(i), Some(j))
+        let middle = height / 2;
+        let mut row = top.to_vec();
+        let mut next = vec![0.0; width + 1];
+        for (i, &first) in left.iter().enumerate().take(middle + 1).skip(1) {
+            next[0] = first;
+            for j in 1..=width {
+                next[j] = self.step(r0 + i, c0 + j, row[j - 1], row[j], next[j - 1]).0;
             }
-            Choice::A => {
-                i -= 1;
-                (Some(i), None)
+            std::mem::swap(&mut row, &mut next);
+        }
+        let middle_row = row.clone();
+
+        // Column at which the path ending in every cell of the current row leaves the middle row.
+        let mut crossing = (0..=width).collect::<Vec<_>>();
+        let mut next_crossing = crossing.clone();
+        for (i, &first) in left.iter().enumerate().skip(middle + 1) {
+            next[0] = first;
+            next_crossing[0] = crossing[0];
+            for j in 1..=width {
+                let choice;
+                (next[j], choice) = self.step(r0 + i, c0 + j, row[j - 1], row[j], next[j - 1]);
+                next_crossing[j] = match choice {
+                    Choice::Match => crossing[j - 1],
+                    Choice::A => crossing[j],
+                    Choice::B => next_crossing[j - 1],
+                };
             }
-            Choice::B => {
-                j -= 1;
-                (None, Some(j))
+            std::mem::swap(&mut row, &mut next);
+            std::mem::swap(&mut crossing, &mut next_crossing);
+        }
+        let k = crossing[width];
+
+        // The costs of column `k` below the middle row are needed as the first column of the second part.
+        let mut column = Vec::with_capacity(height - middle + 1);
+        column.push(middle_row[k]);
+        row[..=k].copy_from_slice(&middle_row[..=k]);
+        for (i, &first) in left.iter().enumerate().skip(middle + 1) {
+            next[0] = first;
+            for j in 1..=k {
+                next[j] = self.step(r0 + i, c0 + j, row[j - 1], row[j], next[j - 1]).0;
             }
-        })
+            std::mem::swap(&mut row, &mut next);
+            column.push(row[k]);
+        }
+        drop((row, next, crossing, next_crossing));
+
+        self.align_region_linear(r0 + middle, c0 + k, &middle_row[k..], &column, out);
+        self.align_region_linear(r0, c0, &top[..=k], &left[..=middle], out);
     }
 
+    /// Aligns the items `r0..r1` of `a` with the items `c0..c1` of `b` with
+    /// [`Costs::align_region_linear`], considering every cell of that window.
+    fn align_window_linear(
+        &mut self,
+        (r0, r1): (usize, usize),
+        (c0, c1): (usize, usize),
+    ) -> Vec<Pair> {
+        let mut left = vec![0.0; r1 - r0 + 1];
+        for i in 1..left.len() {
+            left[i] = left[i - 1] + self.a_gaps[r0 + i - 1];
+        }
+        let mut top = vec![0.0; c1 - c0 + 1];
+        for j in 1..top.len() {
+            top[j] = top[j - 1] + self.b_gaps[c0 + j - 1];
+        }
+
+        let mut alignment = vec![];
+        self.align_region_linear(r0, c0, &top, &left, &mut alignment);
+        alignment.reverse();
+        alignment
+    }
+
+    /// Aligns the items `r0..r1` of `a` with the items `c0..c1` of `b`, only considering cells
+    /// within `band` cells of the diagonal of that window.
+    ///
+    /// Returns the alignment, its total cost and whether the path touched the edge of the band, in
+    /// which case a wider band might give a better alignment.
+    fn align_band(
+        &mut self,
+        (r0, r1): (usize, usize),
+        (c0, c1): (usize, usize),
+        band: usize,
+    ) -> (Vec<Pair>, f64, bool) {
+        let height = r1 - r0;
+        let width = c1 - c0;
+        let diagonal = |i: usize| (i * width).checked_div(height).unwrap_or(0);
+
+        // Every row covers the columns from where the diagonal enters it to where it leaves it, widened by
+        // `band` on both sides.
+        let mut rows: Vec<(usize, usize, Vec<f64>, Vec<Choice>)> = Vec::with_capacity(height + 1);
+        for i in 0..=height {
+            let lo = diagonal(i).saturating_sub(band);
+            let hi = if i == height {
+                width
+            } else {
+                (diagonal(i + 1) + 1 + band).min(width)
+            };
+            let mut costs = Vec::with_capacity(hi - lo + 1);
+            let mut choices = Vec::with_capacity(hi - lo + 1);
+
+            let cost_at = |rows: &[(usize, usize, Vec<f64>, Vec<Choice>)], i: usize, j: usize| {
+                let (lo, hi, costs, _) = &rows[i];
+                if (*lo..=*hi).contains(&j) {
+                    costs[j - lo]
+                } else {
+                    f64::INFINITY
+                }
+            };
+
+            for j in lo..=hi {
+                let left = if j > lo {
+                    costs[j - lo - 1]
+                } else {
+                    f64::INFINITY
+                };
+                let (cost, choice) = if i == 0 && j == 0 {
+                    (0.0, Choice::A)
+                } else if i == 0 {
+                    (left + self.b_gaps[c0 + j - 1], Choice::B)
+                } else if j == 0 {
+                    (
+                        cost_at(&rows, i - 1, 0) + self.a_gaps[r0 + i - 1],
+                        Choice::A,
+                    )
+                } else {
+                    self.step(
+                        r0 + i,
+                        c0 + j,
+                        cost_at(&rows, i - 1, j - 1),
+                        cost_at(&rows, i - 1, j),
+                        left,
+                    )
+                };
+                costs.push(cost);
+                choices.push(choice);
+            }
+
+            rows.push((lo, hi, costs, choices));
+        }
+
+        let total = rows[height].2[width - rows[height].0];
+        let mut touched = false;
+        let mut alignment = vec![];
+        let mut i = height;
+        let mut j = width;
+        while i > 0 || j > 0 {
+            let (lo, hi, _, choices) = &rows[i];
+            touched |= (j == *lo && *lo > 0) || (j == *hi && *hi < width);
+            alignment.push(match choices[j - lo] {
+                Choice::Match => {
+                    i -= 1;
+                    j -= 1;
+                    (Some(r0 + i), Some(c0 + j))
+                }
+                Choice::A => {
+                    i -= 1;
+                    (Some(r0 + i), None)
+                }
+                Choice::B => {
+                    j -= 1;
+                    (None, Some(c0 + j))
+                }
+            })
+        }
+        alignment.reverse();
+
+        (alignment, total, touched)
+    }
+}
+
+/// [Needleman-Wunsch](https://en.wikipedia.org/wiki/Needleman%E2%80%93Wunsch_algorithm) sequence
+/// alignment algorithm but minimizing cost instead of maximizing score and with distinct gap costs
+/// for items.
+pub fn align(
+    a: usize,
+    b: usize,
+    gap_cost_for: impl FnMut(bool, usize) -> f64,
+    pairwise_cost: impl FnMut(usize, usize) -> f64,
+) -> Vec<(Option<usize>, Option<usize>)> {
+    let (mut costs, left, top) = Costs::new(a, b, gap_cost_for, pairwise_cost);
+
+    let mut alignment = vec![];
+    costs.align_region_full(0, 0, &top, &left, &mut alignment);
     alignment.reverse();
 
     alignment
 }
 
-fn katakana2hiragana(chr: char) -> char {
+/// Variant of [`align`] using memory linear in `a + b` instead of `a * b`, at the cost of roughly
+/// three times the computation.
+///
+/// Based on [Hirschberg's algorithm](https://en.wikipedia.org/wiki/Hirschberg%27s_algorithm) but
+/// always returns exactly the same alignment as [`align`], even when there are several optimal ones.
+pub fn align_linear(
+    a: usize,
+    b: usize,
+    gap_cost_for: impl FnMut(bool, usize) -> f64,
+    pairwise_cost: impl FnMut(usize, usize) -> f64,
+) -> Vec<(Option<usize>, Option<usize>)> {
+    let (mut costs, left, top) = Costs::new(a, b, gap_cost_for, pairwise_cost);
+
+    let mut alignment = vec![];
+    costs.align_region_linear(0, 0, &top, &left, &mut alignment);
+    alignment.reverse();
+
+    alignment
+}
+
+/// Variant of [`align`] which only aligns the items between consecutive `anchors`, pairs of items
+/// which are known to match, and only near the diagonal of every such window.
+///
+/// The band around the diagonal is widened as long as the path runs along its edge, or when the
+/// average cost of the window is high and widening it lowers that cost. Anchors must be strictly
+/// increasing on both sides.
+///
+/// A window whose band would take as much memory as [`align`] does for long sequences, like when
+/// there are few anchors, is aligned with [`align_linear`] instead.
+pub fn align_anchored(
+    a: usize,
+    b: usize,
+    anchors: &[(usize, usize)],
+    gap_cost_for: impl FnMut(bool, usize) -> f64,
+    pairwise_cost: impl FnMut(usize, usize) -> f64,
+) -> Vec<(Option<usize>, Option<usize>)> {
+    align_anchored_within(
+        a,
+        b,
+        anchors,
+        gap_cost_for,
+        pairwise_cost,
+        LINEAR_ALIGNMENT_CELLS,
+    )
+}
+
+/// [`align_anchored`] with bands of at most about `max_cells` cells.
+fn align_anchored_within(
+    a: usize,
+    b: usize,
+    anchors: &[(usize, usize)],
+    gap_cost_for: impl FnMut(bool, usize) -> f64,
+    pairwise_cost: impl FnMut(usize, usize) -> f64,
+    max_cells: usize,
+) -> Vec<(Option<usize>, Option<usize>)> {
+    const INITIAL_BAND: usize = 16;
+    const BAD_AVERAGE_COST: f64 = 0.5;
+
+    let (mut costs, _, _) = Costs::new(a, b, gap_cost_for, pairwise_cost);
+
+    let mut alignment = vec![];
+    let mut previous = (0, 0);
+    for &(ai, bi) in anchors.iter().chain(std::iter::once(&(a, b))) {
+        let rows = (previous.0, ai);
+        let columns = (previous.1, bi);
+        let (height, width) = (ai - previous.0, bi - previous.1);
+        let size = std::cmp::max(height, width);
+
+        let mut band = INITIAL_BAND;
+        let mut previous_cost = f64::INFINITY;
+        let window = loop {
+            // Every row of the band covers the columns the diagonal passes through plus `band` on
+            // both sides.
+            let row_cells = (width / height.max(1) + 2 * band + 2).min(width + 1);
+            if (height + 1) * row_cells > max_cells {
+                break costs.align_window_linear(rows, columns);
+            }
+
+            let (window, cost, touched) = costs.align_band(rows, columns, band);
+            let bad = cost / window.len().max(1) as f64 > BAD_AVERAGE_COST && cost < previous_cost;
+            if band >= size || !(touched || bad) {
+                break window;
+            }
+            previous_cost = cost;
+            band *= 2;
+        };
+        alignment.extend(window);
+
+        if ai < a || bi < b {
+            alignment.push((Some(ai), Some(bi)));
+        }
+        previous = (ai + 1, bi + 1);
+    }
+
+    alignment
+}
+
+pub(crate) fn katakana2hiragana(chr: char) -> char {
     let value = chr as u32;
     if (0x30A1..=0x30F4).contains(&value) {
         char::from_u32(value - 96).unwrap()
@@ -151,28 +501,311 @@ fn pairwise_cost(i: usize, j: usize, al: &TokenInfo, bl: &TokenInfo) -> f64 {
     2.0 - length_term + pos_term
 }
 
+/// Finds tokens which appear exactly once in both `a` and `b` and keeps the longest chain of them
+/// that occurs in the same order on both sides.
+fn find_anchors(a: &[TokenInfo], b: &[TokenInfo]) -> Vec<(usize, usize)> {
+    let mut occurrences: HashMap<&str, [(usize, usize); 2]> = HashMap::new();
+    for (side, tokens) in [a, b].into_iter().enumerate() {
+        for (i, token) in tokens.iter().enumerate() {
+            let key = token.normalized.trim();
+            if key.chars().any(char::is_alphanumeric) {
+                let entry = &mut occurrences.entry(key).or_default()[side];
+                *entry = (entry.0 + 1, i);
+            }
+        }
+    }
+
+    let mut candidates = occurrences
+        .into_values()
+        .filter(|[(a_count, _), (b_count, _)]| *a_count == 1 && *b_count == 1)
+        .map(|[(_, ai), (_, bi)]| (ai, bi))
+        .collect::<Vec<_>>();
+    candidates.sort_unstable();
+
+    // Longest increasing subsequence of the indices into `b`.
+    let mut tails: Vec<usize> = vec![];
+    let mut predecessors = vec![None; candidates.len()];
+    for (k, &(_, bi)) in candidates.iter().enumerate() {
+        let position = tails.partition_point(|&tail| candidates[tail].1 < bi);
+        predecessors[k] = position.checked_sub(1).map(|p| tails[p]);
+        if position == tails.len() {
+            tails.push(k);
+        } else {
+            tails[position] = k;
+        }
+    }
+
+    let mut anchors = vec![];
+    let mut current = tails.last().copied();
+    while let Some(k) = current {
+        anchors.push(candidates[k]);
+        current = predecessors[k];
+    }
+    anchors.reverse();
+
+    anchors
+}
+
+/// Which variant of [`align`] [`text_align`] used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlignStrategy {
+    /// [`align`] on the whole matrix.
+    Full,
+    /// [`align_linear`], since the whole matrix would take too much memory.
+    Linear,
+    /// [`align_anchored`] with this many anchors.
+    Anchored { anchors: usize },
+}
+
 /// [`align`] specialized for aligning sequences of strings.
+///
+/// With `anchored` set, [`align_anchored`] is used with unique tokens as anchors instead of aligning
+/// the whole sequences at once.
+///
+/// Every aligned pair is returned together with its cost, that is the result of `pairwise_cost` for
+/// matched items and the gap cost for unmatched ones.
 pub fn text_align(
     a: impl Iterator<Item = String>,
     b: impl Iterator<Item = String>,
-) -> Vec<(Option<usize>, Option<usize>)> {
+    anchored: bool,
+) -> (Vec<CostedPair>, AlignStrategy) {
     let an = a.into_iter().map(TokenInfo::new).collect::<Vec<_>>();
     let bn = b.into_iter().map(TokenInfo::new).collect::<Vec<_>>();
 
-    align(
-        an.len(),
-        bn.len(),
-        |is_b, i| {
-            if if is_b { &bn } else { &an }[i]
-                .text
-                .chars()
-                .any(char::is_alphanumeric)
-            {
-                1.0
-            } else {
-                0.1
+    let gap_cost_for = |is_b: bool, i: usize| {
+        if if is_b { &bn } else { &an }[i]
+            .text
+            .chars()
+            .any(char::is_alphanumeric)
+        {
+            1.0
+        } else {
+            0.1
+        }
+    };
+
+    let scaled_pairwise_cost = |ai, bi| pairwise_cost(ai, bi, &an[ai], &bn[bi]) * 0.99;
+
+    let (alignment, strategy) = if anchored {
+        let anchors = find_anchors(&an, &bn);
+        let alignment = align_anchored(
+            an.len(),
+            bn.len(),
+            &anchors,
+            gap_cost_for,
+            scaled_pairwise_cost,
+        );
+        let anchors = anchors.len();
+        (alignment, AlignStrategy::Anchored { anchors })
+    } else if (an.len() + 1) * (bn.len() + 1) > LINEAR_ALIGNMENT_CELLS {
+        let alignment = align_linear(an.len(), bn.len(), gap_cost_for, scaled_pairwise_cost);
+        (alignment, AlignStrategy::Linear)
+    } else {
+        let alignment = align(an.len(), bn.len(), gap_cost_for, scaled_pairwise_cost);
+        (alignment, AlignStrategy::Full)
+    };
+
+    let alignment = alignment
+        .into_iter()
+        .map(|(ai, bi)| {
+            let cost = match (ai, bi) {
+                (Some(ai), Some(bi)) => pairwise_cost(ai, bi, &an[ai], &bn[bi]),
+                (Some(ai), None) => gap_cost_for(false, ai),
+                (None, Some(bi)) => gap_cost_for(true, bi),
+                (None, None) => unreachable!(),
+            };
+            (ai, bi, cost)
+        })
+        .collect();
+
+    (alignment, strategy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic pseudo-random numbers below `bound`.
+    fn numbers(seed: u64, count: usize, bound: u64) -> Vec<u64> {
+        let mut state = seed;
+        (0..count)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 33) % bound
+            })
+            .collect()
+    }
+
+    /// Total cost of `alignment`, which has to cover both sequences in order.
+    fn score(
+        alignment: &[Pair],
+        a: usize,
+        b: usize,
+        gap_cost_for: impl Fn(bool, usize) -> f64,
+        pairwise_cost: impl Fn(usize, usize) -> f64,
+    ) -> f64 {
+        let a_items = alignment
+            .iter()
+            .filter_map(|pair| pair.0)
+            .collect::<Vec<_>>();
+        let b_items = alignment
+            .iter()
+            .filter_map(|pair| pair.1)
+            .collect::<Vec<_>>();
+        assert_eq!(a_items, (0..a).collect::<Vec<_>>());
+        assert_eq!(b_items, (0..b).collect::<Vec<_>>());
+
+        alignment
+            .iter()
+            .map(|pair| match *pair {
+                (Some(i), Some(j)) => pairwise_cost(i, j),
+                (Some(i), None) => gap_cost_for(false, i),
+                (None, Some(j)) => gap_cost_for(true, j),
+                (None, None) => unreachable!(),
+            })
+            .sum()
+    }
+
+    #[test]
+    fn ties_prefer_matching() {
+        let alignment = align(1, 1, |_, _| 1.0, |_, _| 2.0);
+        assert_eq!(alignment, [(Some(0), Some(0))]);
+        assert_eq!(align_linear(1, 1, |_, _| 1.0, |_, _| 2.0), alignment);
+    }
+
+    #[test]
+    fn linear_matches_full() {
+        // Few distinct items and round costs give many optimal alignments, large enough to be split
+        // several times by `align_linear`.
+        for (seed, (a, b)) in [(300, 300), (520, 150), (140, 610), (400, 401)]
+            .into_iter()
+            .enumerate()
+        {
+            let seed = seed as u64;
+            let a_items = numbers(seed, a, 4);
+            let b_items = numbers(seed + 100, b, 4);
+            let gap_cost_for = |is_b: bool, i: usize| {
+                let item = if is_b { b_items[i] } else { a_items[i] };
+                if item == 0 {
+                    0.5
+                } else {
+                    1.0
+                }
+            };
+            let pairwise_cost = |i: usize, j: usize| {
+                if a_items[i] == b_items[j] {
+                    0.0
+                } else {
+                    1.5
+                }
+            };
+
+            let full = align(a, b, gap_cost_for, pairwise_cost);
+            let linear = align_linear(a, b, gap_cost_for, pairwise_cost);
+            assert_eq!(
+                score(&linear, a, b, gap_cost_for, pairwise_cost),
+                score(&full, a, b, gap_cost_for, pairwise_cost)
+            );
+            assert_eq!(linear, full);
+        }
+    }
+
+    fn tokens(text: &str) -> Vec<TokenInfo> {
+        text.split(' ')
+            .map(|word| TokenInfo::new(word.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn anchors_in_order() {
+        let a = tokens("the cat sat on the mat");
+        let b = tokens("On the mat the cat sat");
+        assert_eq!(find_anchors(&a, &b), [(3, 0), (5, 2)]);
+    }
+
+    #[test]
+    fn anchors_unique_and_increasing() {
+        for seed in 0..20 {
+            let words = |seed| {
+                numbers(seed, 200, 150)
+                    .into_iter()
+                    .map(|n| TokenInfo::new(format!("w{n}")))
+                    .collect::<Vec<_>>()
+            };
+            let a = words(seed);
+            let b = words(seed + 1000);
+            let count = |tokens: &[TokenInfo], text: &str| {
+                tokens.iter().filter(|token| token.text == text).count()
+            };
+
+            let anchors = find_anchors(&a, &b);
+            assert!(!anchors.is_empty());
+            for window in anchors.windows(2) {
+                assert!(window[0].0 < window[1].0 && window[0].1 < window[1].1);
             }
-        },
-        |ai, bi| pairwise_cost(ai, bi, &an[ai], &bn[bi]) * 0.99,
-    )
+            for &(ai, bi) in anchors.iter() {
+                let text = &a[ai].text;
+                assert_eq!(&b[bi].text, text);
+                assert_eq!(count(&a, text), 1);
+                assert_eq!(count(&b, text), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn anchored_matches_full() {
+        for seed in 0..10 {
+            let words = |prefix, seed, count| {
+                numbers(seed, count, 150)
+                    .into_iter()
+                    .map(|n| TokenInfo::new(format!("{prefix}{n}")))
+                    .collect::<Vec<_>>()
+            };
+            // A passage of `a` surrounded by unrelated words, which can't be anchors.
+            let a = words("w", seed, 300);
+            let mut b = words("x", seed, 40);
+            b.splice(20..20, words("w", seed, 300).into_iter().skip(30).take(200));
+            // The costs `text_align` uses for words.
+            let gap_cost_for = |_: bool, _: usize| 1.0;
+            let pairwise_cost = |i: usize, j: usize| pairwise_cost(i, j, &a[i], &b[j]) * 0.99;
+            let full = align(a.len(), b.len(), gap_cost_for, pairwise_cost);
+            let full_cost = score(&full, a.len(), b.len(), gap_cost_for, pairwise_cost);
+
+            let anchors = find_anchors(&a, &b);
+            assert!(!anchors.is_empty());
+            for max_cells in [LINEAR_ALIGNMENT_CELLS, 1000] {
+                let anchored = align_anchored_within(
+                    a.len(),
+                    b.len(),
+                    &anchors,
+                    gap_cost_for,
+                    pairwise_cost,
+                    max_cells,
+                );
+                let anchored_cost = score(&anchored, a.len(), b.len(), gap_cost_for, pairwise_cost);
+                assert!(
+                    (anchored_cost - full_cost).abs() < 1e-9,
+                    "{anchored_cost} {full_cost}"
+                );
+                for &(ai, bi) in anchors.iter() {
+                    assert!(anchored.contains(&(Some(ai), Some(bi))));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn anchored_without_anchors_falls_back_to_linear() {
+        let a_items = numbers(7, 300, 4);
+        let b_items = numbers(8, 280, 4);
+        let gap_cost_for = |_: bool, _: usize| 1.0;
+        let pairwise_cost = |i: usize, j: usize| if a_items[i] == b_items[j] { 0.0 } else { 1.5 };
+
+        let full = align(300, 280, gap_cost_for, pairwise_cost);
+        let anchored = align_anchored_within(300, 280, &[], gap_cost_for, pairwise_cost, 1000);
+        assert_eq!(anchored, full);
+    }
 }
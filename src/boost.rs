@@ -0,0 +1,73 @@
+//! Raises the logits of tokens which make up words of the reference, so that whisper spells names
+//! the way the reference does.
+
+use std::{collections::HashSet, ffi::c_void};
+
+use whisper_cpp_sys::{whisper_context, whisper_state, whisper_token, whisper_token_data};
+
+use crate::{error::Result, grammar::words, whisper::tokenize};
+
+pub struct VocabularyBoost {
+    tokens: HashSet<whisper_token>,
+    bonus: f32,
+}
+
+impl VocabularyBoost {
+    /// Collects the tokens of every word of `text`, both with and without a leading space.
+    ///
+    /// Tokens made up only of ASCII punctuation are left out, boosting them would mostly change how
+    /// whisper punctuates.
+    pub unsafe fn new(ctx: *mut whisper_context, text: &str, bonus: f32) -> Result<Self> {
+        let words = words(text)
+            .into_iter()
+            .map(|word| {
+                word.trim_matches(|chr: char| !chr.is_alphanumeric())
+                    .to_string()
+            })
+            .filter(|word| !word.is_empty())
+            .collect::<HashSet<_>>();
+
+        let mut tokens = HashSet::new();
+        for word in words {
+            tokens.extend(tokenize(ctx, &word, usize::MAX)?);
+            tokens.extend(tokenize(ctx, &format!(" {word}"), usize::MAX)?);
+        }
+        tokens.retain(|&token| {
+            let text = std::ffi::CStr::from_ptr(whisper_cpp_sys::whisper_token_to_str(ctx, token));
+            !text
+                .to_bytes()
+                .iter()
+                .all(|byte| byte.is_ascii() && !byte.is_ascii_alphanumeric())
+        });
+
+        Ok(VocabularyBoost { tokens, bonus })
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn contains(&self, token: whisper_token) -> bool {
+        self.tokens.contains(&token)
+    }
+
+    /// Points the logits filter of whisper at this boost, which has to outlive `params`.
+    pub fn apply(&self, params: &mut whisper_cpp_sys::whisper_full_params) {
+        params.logits_filter_callback = Some(add_bonus);
+        params.logits_filter_callback_user_data = self as *const Self as *mut c_void;
+    }
+}
+
+unsafe extern "C" fn add_bonus(
+    _ctx: *mut whisper_context,
+    _state: *mut whisper_state,
+    _tokens: *const whisper_token_data,
+    _n_tokens: i32,
+    logits: *mut f32,
+    user: *mut c_void,
+) {
+    let boost = &*(user as *const VocabularyBoost);
+    for &token in boost.tokens.iter() {
+        *logits.add(token as usize) += boost.bonus;
+    }
+}
@@ -0,0 +1,180 @@
+//! Checkpoints which allow resuming an interrupted transcription.
+//!
+//! A checkpoint is a JSON Lines file starting with the settings of the transcription, followed by
+//! the segments produced by whisper as soon as they are available. Every section of audio whisper
+//! finished is closed by a section record, segments after the last one belong to a section that was
+//! interrupted and are transcribed again when resuming.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Seek, Write},
+    ops::Range,
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Context, Error, Result},
+    ff::StreamSelector,
+    transcription::{DecodingOptions, TimeRange, Token, VadMetadata},
+};
+
+/// Everything that influences the output of whisper, a checkpoint can only be resumed with the same
+/// settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    pub language: String,
+    pub model_sha256: String,
+    pub decoding: DecodingOptions,
+    pub vad: Option<VadMetadata>,
+    #[serde(default)]
+    pub reference_sha256: Option<String>,
+    #[serde(default)]
+    pub audio_name: String,
+    /// `None` if the audio is read as a stream, it's only hashed while being decoded then.
+    #[serde(default)]
+    pub audio_sha256: Option<String>,
+    #[serde(default)]
+    pub audio_stream: Option<StreamSelector>,
+    #[serde(default)]
+    pub range: Option<TimeRange>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record {
+    Settings(Box<Settings>),
+    Segment { tokens: Vec<Token> },
+    Section { start: usize, end: usize },
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckpointOptions {
+    pub path: PathBuf,
+    /// Continue from an existing checkpoint instead of overwriting it.
+    pub resume: bool,
+}
+
+pub struct Checkpoint {
+    writer: BufWriter<File>,
+    /// Segments of the sections finished before resuming, keyed by their range of samples.
+    finished: HashMap<(usize, usize), Vec<Vec<Token>>>,
+}
+
+impl Checkpoint {
+    pub fn open(options: &CheckpointOptions, settings: Settings) -> Result<Self> {
+        let path = &options.path;
+        if !options.resume || !path.exists() {
+            if options.resume {
+                log::info!("No checkpoint found at {}, starting over", path.display());
+            }
+
+            let file = File::create(path)
+                .with_context(|| format!("failed to create checkpoint {}", path.display()))?;
+            let mut checkpoint = Checkpoint {
+                writer: BufWriter::new(file),
+                finished: HashMap::new(),
+            };
+            checkpoint.write(&Record::Settings(Box::new(settings)))?;
+            return Ok(checkpoint);
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("failed to open checkpoint {}", path.display()))?;
+
+        let length = file
+            .metadata()
+            .with_context(|| format!("failed to read checkpoint {}", path.display()))?
+            .len();
+        let mut finished = HashMap::new();
+        let mut pending = vec![];
+        // End of the last record that is still valid, everything after it gets truncated.
+        let mut valid_length = 0;
+        let mut offset = 0;
+        let mut has_settings = false;
+        for line in BufReader::new(&mut file).split(b'\n') {
+            let line =
+                line.with_context(|| format!("failed to read checkpoint {}", path.display()))?;
+            offset += line.len() as u64 + 1;
+            // A line that is missing its newline or can't be parsed was most likely cut off by a
+            // crash, so it has to be the last one.
+            let Some(record) = (offset <= length)
+                .then(|| serde_json::from_slice::<Record>(&line).ok())
+                .flatten()
+            else {
+                break;
+            };
+
+            match record {
+                Record::Settings(previous) => {
+                    if has_settings || *previous != settings {
+                        return Err(Error::Invalid(format!(
+                            "checkpoint {} was made with different settings, remove it or don't resume",
+                            path.display()
+                        )));
+                    }
+                    has_settings = true;
+                    valid_length = offset;
+                }
+                Record::Segment { tokens } => pending.push(tokens),
+                Record::Section { start, end } => {
+                    finished.insert((start, end), std::mem::take(&mut pending));
+                    valid_length = offset;
+                }
+            }
+        }
+
+        if !has_settings {
+            return Err(Error::Invalid(format!(
+                "checkpoint {} is not a valid checkpoint",
+                path.display()
+            )));
+        }
+
+        log::info!(
+            "Resuming from {} with {} finished sections",
+            path.display(),
+            finished.len()
+        );
+        file.set_len(valid_length)
+            .and_then(|_| file.seek(std::io::SeekFrom::End(0)))
+            .with_context(|| format!("failed to truncate checkpoint {}", path.display()))?;
+
+        Ok(Checkpoint {
+            writer: BufWriter::new(file),
+            finished,
+        })
+    }
+
+    fn write(&mut self, record: &Record) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, record)
+            .map_err(std::io::Error::from)
+            .and_then(|_| self.writer.write_all(b"\n"))
+            .and_then(|_| self.writer.flush())
+            .context("failed to write checkpoint")
+    }
+
+    /// Returns the segments of `section` if it was already transcribed before resuming, they are
+    /// still part of the checkpoint file.
+    pub fn take_finished(&mut self, section: &Range<usize>) -> Option<Vec<Vec<Token>>> {
+        self.finished.remove(&(section.start, section.end))
+    }
+
+    pub fn write_segment(&mut self, tokens: &[Token]) -> Result<()> {
+        self.write(&Record::Segment {
+            tokens: tokens.to_vec(),
+        })
+    }
+
+    pub fn finish_section(&mut self, section: &Range<usize>) -> Result<()> {
+        self.write(&Record::Section {
+            start: section.start,
+            end: section.end,
+        })
+    }
+}
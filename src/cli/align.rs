@@ -1,109 +1,90 @@
-use std::{fs::File, io::Write, mem::ManuallyDrop, ops::Range, path::PathBuf};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
 
 use clap::Parser;
+use regex::Regex;
 #[cfg(feature = "vibrato")]
-use vibrato::{tokenizer::worker::Worker, Dictionary};
-
-use crate::whisper::Transcription;
+use whisper_aligner::VibratoTokenizer;
+use whisper_aligner::{
+    error::Context, output::ass_template_style, timing::TimeSource, AlignOptions, AlignStrategy,
+    MarkupRule, MarkupRules, OutputFormat, Reference, ReferenceFormat, Result, TimedLine,
+    Tokenizer, Transcription, WhitespaceTokenizer, WriteOptions,
+};
 
 #[derive(Parser)]
 pub struct Opts {
     transcription: PathBuf,
+    /// Plain text with one line per cue, a SubRip, WebVTT or ASS file whose cues are re-timed, or
+    /// LRC lyrics.
     reference: PathBuf,
+    /// Inferred from the extension of the reference file if not specified, anything unknown is
+    /// read as plain text.
+    #[clap(long)]
+    reference_format: Option<ReferenceFormat>,
+    /// Transcription of a part of the audio made with `transcribe --start/--end`, replacing that
+    /// part of the main transcription. Can be given multiple times, later ones take precedence.
+    #[clap(long)]
+    merge: Vec<PathBuf>,
+    #[clap(short, long, alias = "output-vtt")]
+    output: PathBuf,
+    /// Inferred from the extension of the output file if not specified.
+    #[clap(long)]
+    output_format: Option<OutputFormat>,
+    /// Script whose header and styles will be copied into the output when writing ASS.
+    #[clap(long)]
+    ass_template: Option<PathBuf>,
+    /// Output a separate cue for every word instead of every line.
+    #[clap(long)]
+    word_cues: bool,
+    /// Add inline timestamps before every word of a WebVTT cue, or enhanced LRC word timestamps.
+    #[clap(long)]
+    karaoke: bool,
+    /// Time whisper tokens using their DTW timestamps, requires a transcription made with `--dtw`.
     #[clap(long)]
-    output_vtt: PathBuf,
+    prefer_dtw: bool,
+    /// Only align tokens near unique words found in both texts, much faster for multi-hour audio.
+    #[clap(long)]
+    anchored: bool,
+    /// Spans of the reference which aren't spoken, either a pair of brackets like `[]` or a regular
+    /// expression. They are kept in the output but not aligned, can be given multiple times.
+    #[clap(long)]
+    markup: Vec<MarkupRule>,
+    /// Regular expression matching speaker labels like `^([A-Z]+):`, its first capture group is
+    /// the name of the speaker. Labels are kept in the output but not aligned.
+    #[clap(long)]
+    speaker_label: Option<Regex>,
+    /// Move speaker labels out of the text, into WebVTT voice tags or the name field of ASS. SubRip
+    /// and LRC output keep them as `Speaker: ` in front of the text.
+    #[clap(long, requires = "speaker_label")]
+    voice_tags: bool,
+    /// Language of the reference, overrides the one recorded in the transcription.
+    #[clap(short, long)]
+    language: Option<String>,
+    /// Write a JSON report describing how every line was timed.
+    #[clap(long)]
+    report_json: Option<PathBuf>,
+    /// Print every line with its times and aligned token pairs.
+    #[clap(short, long)]
+    verbose: bool,
     #[cfg(feature = "vibrato")]
     #[clap(long)]
     vibrato_dictionary: Option<PathBuf>,
 }
 
-trait Tokenizer {
-    fn tokenize<'a>(&'a mut self, text: &'a str) -> Box<dyn Iterator<Item = Range<usize>> + 'a>;
-}
-
-#[cfg(feature = "vibrato")]
-struct VibratoTokenizer {
-    tokenizer: *mut vibrato::Tokenizer,
-    worker: ManuallyDrop<Worker<'static>>,
-}
-
-#[cfg(feature = "vibrato")]
-impl VibratoTokenizer {
-    fn new(dictionary: Dictionary) -> Self {
-        let tokenizer: *mut vibrato::Tokenizer =
-            Box::leak(Box::new(vibrato::Tokenizer::new(dictionary))) as *mut _;
-        let worker = unsafe { (*tokenizer).new_worker() };
-        Self {
-            tokenizer,
-            worker: ManuallyDrop::new(worker),
-        }
-    }
-}
-
-#[cfg(feature = "vibrato")]
-impl Drop for VibratoTokenizer {
-    fn drop(&mut self) {
-        unsafe {
-            ManuallyDrop::drop(&mut self.worker);
-            let _ = Box::from_raw(self.tokenizer);
-        }
-    }
-}
-
-#[cfg(feature = "vibrato")]
-impl Tokenizer for VibratoTokenizer {
-    fn tokenize<'a>(&'a mut self, text: &'a str) -> Box<dyn Iterator<Item = Range<usize>> + 'a> {
-        self.worker.reset_sentence(text);
-        self.worker.tokenize();
-        Box::new(self.worker.token_iter().map(|x| x.range_byte()))
-    }
-}
-
-struct WhitespaceTokenizer;
-
-impl Tokenizer for WhitespaceTokenizer {
-    fn tokenize<'a>(&'a mut self, text: &'a str) -> Box<dyn Iterator<Item = Range<usize>> + 'a> {
-        let mut current = text
-            .find(|c: char| !c.is_whitespace())
-            .unwrap_or(text.len());
-        let mut next_whitespace = None;
-
-        Box::new(std::iter::from_fn(move || {
-            if let Some(ws) = next_whitespace.take() {
-                return Some(ws);
-            }
-
-            if current == text.len() {
-                return None;
-            }
-
-            let next = text[current..]
-                .find(char::is_whitespace)
-                .map(|x| x + current)
-                .unwrap_or(text.len());
-
-            let old_current = current;
-            current = text[next..]
-                .find(|c: char| !c.is_whitespace())
-                .map(|x| x + next)
-                .unwrap_or(text.len());
-            if next != text.len() {
-                next_whitespace = Some(next..current);
-            }
-
-            Some(old_current..next)
-        }))
-    }
-}
-
-fn create_tokenizer(opts: &Opts, language: &str) -> Box<dyn Tokenizer> {
+#[cfg_attr(not(feature = "vibrato"), allow(unused_variables))]
+fn create_tokenizer(opts: &Opts, language: &str) -> Result<Box<dyn Tokenizer>> {
     if language == "ja" {
         #[cfg(feature = "vibrato")]
         if let Some(dic) = opts.vibrato_dictionary.as_ref() {
             println!("Loading vibrato dictionary");
-            return Box::new(VibratoTokenizer::new(
-                vibrato::Dictionary::read(std::fs::File::open(dic).unwrap()).unwrap(),
-            ));
+            let file = File::open(dic)
+                .with_context(|| format!("failed to open vibrato dictionary {}", dic.display()))?;
+            let dictionary = vibrato::Dictionary::read(file)
+                .with_context(|| format!("failed to read vibrato dictionary {}", dic.display()))?;
+            return Ok(Box::new(VibratoTokenizer::new(dictionary)));
         } else {
             eprintln!(
                 "[warning] No vibrato dictionary was provided but Japanese is being tokenized."
@@ -116,227 +97,251 @@ fn create_tokenizer(opts: &Opts, language: &str) -> Box<dyn Tokenizer> {
         eprintln!("[warning] This will result in terrible alignment quality, consider using the vibrato tokenizer instead.");
     }
 
-    Box::new(WhitespaceTokenizer)
+    Ok(Box::new(WhitespaceTokenizer))
 }
 
-struct TimedLine {
-    start: i64,
-    end: i64,
-    text: String,
+fn is_japanese(chr: char) -> bool {
+    matches!(chr, '\u{3040}'..='\u{30ff}')
 }
 
-fn timed_lines_to_vtt<'a>(
-    language: &str,
-    lines: impl IntoIterator<Item = &'a TimedLine>,
-    mut output: impl Write,
-) {
-    let vtt_ts = |ts: i64| {
-        let ms = ts * 10;
-        let s = ms / 1000;
-        let min = s / 60;
-        let h = min / 60;
-        format!("{:02}:{:02}:{:02}.{:<03}", h, min % 60, s % 60, ms % 1000)
-    };
+/// Warns about languages which don't seem to fit the reference, the tokenizer is picked based on
+/// the language so a wrong one results in bad alignment.
+fn check_language(transcription: &Transcription, language: &str, reference: &str) {
+    if let Some(probability) = transcription
+        .language_probability
+        .filter(|probability| *probability < 0.5)
+    {
+        eprintln!(
+            "[warning] The language {} was detected with a probability of only {:.1}%, pass --language if it is wrong.",
+            transcription.language,
+            probability * 100.
+        );
+    }
 
-    writeln!(output, "WEBVTT").unwrap();
-    writeln!(output, "Kind: captions").unwrap();
-    writeln!(output, "Languagee: {language}").unwrap();
-    for line in lines.into_iter() {
-        writeln!(output).unwrap();
-        writeln!(output, "{} --> {}", vtt_ts(line.start), vtt_ts(line.end)).unwrap();
-        writeln!(output, "{}", line.text).unwrap();
+    let (japanese, total) = reference
+        .chars()
+        .filter(|chr| chr.is_alphanumeric())
+        .fold((0, 0), |(japanese, total), chr| {
+            (japanese + is_japanese(chr) as usize, total + 1)
+        });
+    if language == "ja" && total > 0 && japanese == 0 {
+        eprintln!("[warning] The language is Japanese but the reference contains no kana, pass --language if it is wrong.");
+    } else if language != "ja" && japanese * 5 > total {
+        eprintln!("[warning] The reference looks Japanese but the language is {language}, pass --language ja if it is wrong.");
     }
 }
 
-pub fn main(opts: Opts) {
-    let transcription: Transcription =
-        serde_json::from_reader(File::open(&opts.transcription).unwrap()).unwrap();
-    let reference = std::fs::read_to_string(&opts.reference).unwrap();
-
-    let mut tokenizer = create_tokenizer(&opts, &transcription.language);
-
-    #[derive(Clone, Debug)]
-    struct WhisperToken {
-        text: String,
-        start: i64,
-        end: i64,
-    }
+fn read_transcription(path: &Path) -> Result<Transcription> {
+    Transcription::from_reader(std::io::BufReader::new(
+        File::open(path)
+            .with_context(|| format!("failed to open transcription {}", path.display()))?,
+    ))
+    .with_context(|| format!("failed to parse transcription {}", path.display()))
+}
 
-    let mut byte_starts = vec![];
-    let mut byte_ends = vec![];
-    let mut whisper_sentence = String::new();
-    for segment in transcription.segments.iter() {
-        for token in segment {
-            let bytes = token.text.len();
-            let byte_duration = (token.end - token.start) / bytes as i64;
-            let mut current = token.start;
-            for _ in 0..bytes {
-                byte_starts.push(current);
-                current += byte_duration;
-                byte_ends.push(current);
+pub fn main(opts: Opts) -> Result<()> {
+    let mut transcription = read_transcription(&opts.transcription)?;
+    for path in opts.merge.iter() {
+        let part = read_transcription(path)?;
+        match part.range {
+            Some(range) => println!("Merging {} covering {range}", path.display()),
+            None => eprintln!(
+                "[warning] {} covers the whole audio, it replaces everything merged before it.",
+                path.display()
+            ),
+        }
+        let audio_sha256 = |transcription: &Transcription| {
+            transcription
+                .metadata
+                .as_ref()
+                .map(|metadata| metadata.audio.sha256.clone())
+        };
+        if let (Some(a), Some(b)) = (audio_sha256(&transcription), audio_sha256(&part)) {
+            if a != b {
+                eprintln!(
+                    "[warning] {} was made from different audio than {}.",
+                    path.display(),
+                    opts.transcription.display()
+                );
             }
-            whisper_sentence += &token.text
         }
-
-        byte_starts.push(*byte_ends.last().unwrap());
-        byte_ends.push(*byte_ends.last().unwrap());
-        whisper_sentence += "\n";
-    }
-    assert_eq!(whisper_sentence.len(), byte_ends.len());
-    assert_eq!(whisper_sentence.len(), byte_starts.len());
-
-    println!("Tokenizing whisper sentence");
-    let whisper_tokens = tokenizer
-        .tokenize(&whisper_sentence)
-        .map(|range| WhisperToken {
-            text: whisper_sentence[range.clone()].to_string(),
-            start: byte_starts[range.start],
-            end: byte_ends[range.end - 1],
-        })
-        .collect::<Vec<_>>();
-
-    println!("Tokenizing reference sentence");
-    let reference_tokens = tokenizer
-        .tokenize(&reference)
-        .map(|x| &reference[x])
-        .collect::<Vec<_>>();
-
-    println!("Aligning tokens");
-    let alignment = crate::align::text_align(
-        whisper_tokens.iter().map(|x| x.text.clone()),
-        reference_tokens.iter().copied().map(str::to_string),
-    )
-    .into_iter()
-    .map(|(a, b)| {
-        (
-            a.map(|i| &whisper_tokens[i]),
-            b.map(|i| reference_tokens[i]),
-        )
-    })
-    .collect::<Vec<_>>();
-
-    let mut reference_lines = vec![vec![]];
-    for (a, b) in alignment {
-        let is_line_boundary = b.as_ref().is_some_and(|x| x.contains("\n"));
-        reference_lines.last_mut().unwrap().push((a, b));
-        if is_line_boundary {
-            reference_lines.push(vec![]);
+        if part.language != transcription.language {
+            eprintln!(
+                "[warning] {} is in {} but {} is in {}.",
+                path.display(),
+                part.language,
+                opts.transcription.display(),
+                transcription.language
+            );
         }
+        transcription.merge(part);
     }
-    if reference_lines.last().unwrap().is_empty() {
-        reference_lines.pop();
+    if let Some(metadata) = transcription.metadata.as_ref() {
+        println!(
+            "Transcription of {} made by whisper-aligner {} using the {} model {}",
+            metadata.audio.name,
+            metadata.tool_version,
+            metadata.model.model_type,
+            metadata.model.path.display()
+        );
     }
+    let reference_format = opts
+        .reference_format
+        .unwrap_or_else(|| ReferenceFormat::from_extension(&opts.reference));
+    let reference = Reference::parse(
+        std::fs::read_to_string(&opts.reference)
+            .with_context(|| format!("failed to read reference {}", opts.reference.display()))?,
+        reference_format,
+    )?;
+    let output_format = opts
+        .output_format
+        .or_else(|| OutputFormat::from_extension(&opts.output))
+        .unwrap_or_else(|| {
+            eprintln!(
+                "[warning] Could not infer the output format from {}, writing WebVTT.",
+                opts.output.display()
+            );
+            OutputFormat::Vtt
+        });
 
-    let mut timed_lines: Vec<TimedLine> = vec![];
-
-    for i in 0..reference_lines.len() {
-        let current = &reference_lines[i];
+    if opts.prefer_dtw
+        && !transcription
+            .segments
+            .iter()
+            .flatten()
+            .any(|token| token.dtw.is_some())
+    {
+        eprintln!("[warning] --prefer-dtw was passed but the transcription has no DTW timestamps, was it made with --dtw?");
+    }
 
-        if current.iter().all(|x| x.1.is_none()) {
-            continue;
-        }
+    let language = opts
+        .language
+        .clone()
+        .unwrap_or_else(|| transcription.language.clone());
+    check_language(&transcription, &language, reference.text());
+    let mut tokenizer = create_tokenizer(&opts, &language)?;
 
-        let mut start = None;
-        let mut it = current.iter().peekable();
-        while let Some((Some(x), None)) = it.peek() {
-            start = Some(x.end);
-            it.next();
-        }
-        if let Some((Some(x), Some(_))) = it.next() {
-            start = Some(x.start);
+    let markup = MarkupRules {
+        rules: opts.markup.clone(),
+        speaker_label: opts.speaker_label.clone(),
+    }
+    .apply(reference.text());
+    let alignment = whisper_aligner::align_transcription(
+        &transcription,
+        markup.text(),
+        tokenizer.as_mut(),
+        &AlignOptions {
+            prefer_dtw: opts.prefer_dtw,
+            anchored: opts.anchored,
+            restrict_reference: transcription.range.is_some(),
+        },
+    );
+    match alignment.strategy {
+        AlignStrategy::Full => (),
+        AlignStrategy::Linear => println!("Used linear-memory alignment"),
+        AlignStrategy::Anchored { anchors } => println!("Aligned between {anchors} anchors"),
+    }
+    for line in alignment.report.iter() {
+        if line.start_source == TimeSource::Outside {
+            println!("Skipped line {} (outside the transcribed part)", line.text);
+        } else if line.start.is_none() || line.end.is_none() {
+            println!("Skipped line {} (improperly timed)", line.text);
         }
+    }
 
-        let mut start = if let Some(wt) = start {
-            Some(wt)
-        } else if i > 0 {
-            reference_lines[i - 1]
-                .iter()
-                .rev()
-                .find_map(|x| x.0.as_ref().map(|x| x.end))
-        } else if i == 0 {
-            Some(0)
-        } else {
-            None
-        };
+    if opts.verbose {
+        for (i, line) in alignment.report.iter().enumerate() {
+            if i != 0 {
+                println!()
+            }
 
-        if let (Some(start), Some(last)) = (&mut start, timed_lines.last()) {
-            if *start < last.end {
-                *start = last.end
+            println!("{:?} --- {:?}", line.start, line.end);
+            for pair in line.pairs.iter() {
+                println!("{:?} {:?}", pair.whisper, pair.reference)
             }
+            println!("{}", line.text);
         }
+    }
 
-        let mut end = None;
-        let mut it = current.iter().rev().peekable();
-        while let Some((Some(x), None)) = it.peek().map(|x| {
-            (
-                x.0.as_ref(),
-                x.1.as_ref()
-                    .filter(|x| x.chars().all(|c| c.is_alphanumeric())),
-            )
-        }) {
-            end = Some(x.start);
-            it.next();
-        }
-        while let Some((None, Some(_))) = it.peek().map(|x| {
-            (
-                x.0.as_ref(),
-                x.1.as_ref()
-                    .filter(|x| x.chars().all(|c| !c.is_alphanumeric())),
-            )
-        }) {
-            it.next();
-        }
-        if let Some((Some(x), Some(_))) = it.next() {
-            end = Some(x.end);
-        }
+    if let Some(path) = opts.report_json.as_ref() {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create report {}", path.display()))?;
+        serde_json::to_writer(BufWriter::new(file), &alignment.report)
+            .with_context(|| format!("failed to write report {}", path.display()))?;
+    }
 
-        // println!("{current:?} {:?}", &reference_lines[i + 1]);
-        let end = if let Some(wt) = end {
-            Some(wt)
-        } else if let Some(end) = reference_lines
-            .get(i + 1)
-            .and_then(|v| v.iter().find_map(|x| x.0.as_ref().map(|t| t.start)))
-        {
-            Some(end)
-        } else if i == reference_lines.len() - 1 {
-            byte_ends.last().copied()
-        } else {
-            current
-                .iter()
-                .rev()
-                .find_map(|x| x.0.as_ref().map(|x| x.end))
-        };
+    let restored = markup.restore(alignment.lines, opts.voice_tags);
+    for text in restored.skipped.iter() {
+        eprintln!("[warning] Skipped line {text:?}, it only contains annotations and there is no gap to fill.");
+    }
+    let lines = restored.lines;
 
-        let line_text = current
-            .iter()
-            .filter_map(|x| x.1)
-            .collect::<String>()
-            .trim()
-            .to_string();
-        if let (Some(start), Some(end)) = (start, end) {
-            timed_lines.push(TimedLine {
-                start,
-                end,
-                text: line_text.to_string(),
-            });
-        } else {
-            println!("Skipped line {line_text} (improperly timed)");
+    let mut output = BufWriter::new(
+        File::create(&opts.output)
+            .with_context(|| format!("failed to create output {}", opts.output.display()))?,
+    );
+    match &reference {
+        Reference::Subtitles(subtitles)
+            if subtitles.format().output_format() == Some(output_format) =>
+        {
+            if opts.word_cues || opts.karaoke {
+                eprintln!("[warning] --word-cues and --karaoke are ignored when re-timing a subtitle file.");
+            }
+            let untimed = subtitles
+                .write_retimed(lines.iter(), &mut output)
+                .and_then(|untimed| output.flush().map(|_| untimed))
+                .with_context(|| format!("failed to write output {}", opts.output.display()))?;
+            if untimed > 0 {
+                eprintln!(
+                    "[warning] Kept the original times of {untimed} cues which could not be timed."
+                );
+            }
+            return Ok(());
         }
+        Reference::Subtitles(subtitles) => println!(
+            "Writing plain cues, cue IDs and styling are only kept when writing {}",
+            subtitles.format()
+        ),
+        Reference::Text(_) | Reference::Lyrics(_) => (),
+    }
 
-        if i != 0 {
-            println!()
-        }
+    let mut timed_lines = lines;
+    if opts.word_cues {
+        timed_lines = timed_lines
+            .into_iter()
+            .flat_map(TimedLine::into_word_lines)
+            .collect();
+    }
 
-        println!("{start:?} --- {end:?}");
-        for (a, b) in current {
-            println!("{a:?} {b:?}")
+    let ass_template = opts
+        .ass_template
+        .as_ref()
+        .map(|path| {
+            std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read ASS template {}", path.display()))
+        })
+        .transpose()?;
+    if let Some(template) = ass_template.as_deref() {
+        let style = ass_template_style(template);
+        if output_format == OutputFormat::Ass && style != "Default" {
+            println!("Template has no Default style, using {style} instead");
         }
-        println!("{}", line_text)
     }
-
-    timed_lines_to_vtt(
-        &transcription.language,
-        timed_lines.iter(),
-        std::fs::File::create(opts.output_vtt).unwrap(),
-    );
+    let lrc_headers = match &reference {
+        Reference::Lyrics(lyrics) => lyrics.headers(),
+        _ => &[],
+    };
+    output_format
+        .write(
+            &WriteOptions {
+                language: &language,
+                ass_template: ass_template.as_deref(),
+                lrc_headers,
+                karaoke: opts.karaoke,
+            },
+            timed_lines.iter(),
+            &mut output,
+        )
+        .and_then(|_| output.flush())
+        .with_context(|| format!("failed to write output {}", opts.output.display()))
 }
@@ -1,17 +1,53 @@
 use clap::Parser;
+use whisper_aligner::Result;
 
 mod align;
+#[cfg(feature = "whisper")]
+mod streams;
+#[cfg(feature = "whisper")]
 mod transcribe;
 
+/// Prints the messages of the library, warnings go to stderr like the ones of the CLI itself.
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info && metadata.target().starts_with("whisper_aligner")
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            log::Level::Error | log::Level::Warn => eprintln!("[warning] {}", record.args()),
+            _ => println!("{}", record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
 #[derive(Parser)]
 pub enum Opts {
+    #[cfg(feature = "whisper")]
     Transcribe(transcribe::Opts),
     Align(align::Opts),
+    /// List the audio streams of a file which can be selected with `transcribe --audio-stream`.
+    #[cfg(feature = "whisper")]
+    ListAudioStreams(streams::Opts),
 }
 
-pub fn main(opts: Opts) {
+pub fn main(opts: Opts) -> Result<()> {
+    if log::set_logger(&Logger).is_ok() {
+        log::set_max_level(log::LevelFilter::Info);
+    }
+
     match opts {
+        #[cfg(feature = "whisper")]
         Opts::Transcribe(opts) => transcribe::main(opts),
         Opts::Align(opts) => align::main(opts),
+        #[cfg(feature = "whisper")]
+        Opts::ListAudioStreams(opts) => streams::main(opts),
     }
 }
@@ -0,0 +1,25 @@
+use std::{fs::File, path::PathBuf};
+
+use clap::Parser;
+
+use whisper_aligner::{error::Context, Result};
+
+#[derive(Parser)]
+pub struct Opts {
+    file: PathBuf,
+}
+
+pub fn main(Opts { file }: Opts) -> Result<()> {
+    let streams = whisper_aligner::list_audio_streams(
+        File::open(&file).with_context(|| format!("failed to open {}", file.display()))?,
+    )?;
+
+    if streams.is_empty() {
+        println!("{} has no audio streams", file.display());
+    }
+    for stream in streams {
+        println!("{stream}");
+    }
+
+    Ok(())
+}
@@ -1,20 +1,166 @@
-use std::path::PathBuf;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use clap::Parser;
 
-use crate::whisper::SileroOptions;
+use whisper_aligner::{
+    error::Context, CheckpointOptions, DecodingOptions, Error, Reference, ReferenceFormat, Result,
+    SileroOptions, Strategy, StreamSelector, TimeRange, TranscribeOptions,
+};
 
 #[derive(Parser)]
 pub struct Opts {
+    /// Audio or video file to transcribe, `-` reads it from stdin.
     file: PathBuf,
     #[clap(short, long)]
     output: PathBuf,
     #[clap(short, long)]
     model: PathBuf,
+    /// Whisper language code, detected from the audio if not given.
     #[clap(short, long)]
-    language: String,
+    language: Option<String>,
+    /// Audio stream to transcribe, either its index among the audio streams (see
+    /// `list-audio-streams`), lang:<LANGUAGE TAG> or title:<PART OF THE TITLE>.
+    #[clap(long)]
+    audio_stream: Option<StreamSelector>,
+    /// Only transcribe the audio after this position, in seconds or [HH:]MM:SS[.FF].
+    #[clap(long, value_parser = parse_position)]
+    start: Option<f64>,
+    /// Only transcribe the audio before this position, in seconds or [HH:]MM:SS[.FF].
+    #[clap(long, value_parser = parse_position)]
+    end: Option<f64>,
+    /// Script of the audio, the part expected in each section is used as whisper's prompt. SubRip,
+    /// WebVTT and ASS files are read without their markup.
+    #[clap(long)]
+    reference: Option<PathBuf>,
+    /// Continue an interrupted transcription from the checkpoint next to the output file.
+    #[clap(long)]
+    resume: bool,
     #[clap(flatten)]
     vad: Option<VadOpts>,
+    #[clap(flatten)]
+    decoding: DecodingOpts,
+}
+
+/// Overrides for the decoding options, unset ones are taken from the preset or the defaults.
+#[derive(Parser)]
+pub struct DecodingOpts {
+    /// JSON file with decoding options, missing fields keep their default value.
+    #[clap(long)]
+    decoding_preset: Option<PathBuf>,
+    /// Either greedy or beam-search.
+    #[clap(long)]
+    strategy: Option<Strategy>,
+    #[clap(long)]
+    beam_size: Option<i32>,
+    #[clap(long)]
+    best_of: Option<i32>,
+    #[clap(long)]
+    patience: Option<f32>,
+    #[clap(long)]
+    temperature: Option<f32>,
+    /// Temperature increase for retrying failed decodes, 0 disables the fallback.
+    #[clap(long)]
+    temperature_inc: Option<f32>,
+    #[clap(long)]
+    entropy_thold: Option<f32>,
+    #[clap(long)]
+    logprob_thold: Option<f32>,
+    #[clap(long)]
+    no_speech_thold: Option<f32>,
+    /// Maximum segment length in characters, 0 means no limit.
+    #[clap(long)]
+    max_len: Option<i32>,
+    #[clap(long)]
+    split_on_word: Option<bool>,
+    #[clap(long)]
+    thold_pt: Option<f32>,
+    #[clap(long)]
+    max_initial_ts: Option<f32>,
+    /// Number of threads, 0 uses all available ones.
+    #[clap(long)]
+    threads: Option<i32>,
+    /// Compute token timestamps with DTW over the cross-attention weights of the model.
+    #[clap(long)]
+    dtw: bool,
+    /// Steer decoding towards the words of the reference in order with a grammar, requires
+    /// `--reference`.
+    #[clap(long)]
+    reference_grammar: bool,
+    /// How strongly tokens not matching the reference grammar are penalized.
+    #[clap(long)]
+    grammar_penalty: Option<f32>,
+    /// Added to the logits of tokens making up words of the reference, requires `--reference`.
+    #[clap(long)]
+    vocabulary_bonus: Option<f32>,
+}
+
+impl DecodingOpts {
+    fn into_options(self) -> Result<DecodingOptions> {
+        let mut options = match &self.decoding_preset {
+            Some(path) => {
+                serde_json::from_reader(std::io::BufReader::new(File::open(path).with_context(
+                    || format!("failed to open decoding preset {}", path.display()),
+                )?))
+                .with_context(|| format!("failed to parse decoding preset {}", path.display()))?
+            }
+            None => DecodingOptions::default(),
+        };
+
+        macro_rules! apply {
+            ($($field:ident),*) => {
+                $(if let Some(value) = self.$field {
+                    options.$field = value;
+                })*
+            };
+        }
+        apply!(
+            strategy,
+            beam_size,
+            best_of,
+            patience,
+            temperature,
+            temperature_inc,
+            entropy_thold,
+            logprob_thold,
+            no_speech_thold,
+            max_len,
+            split_on_word,
+            thold_pt,
+            max_initial_ts,
+            threads,
+            grammar_penalty,
+            vocabulary_bonus
+        );
+        options.dtw |= self.dtw;
+        options.reference_grammar |= self.reference_grammar;
+
+        Ok(options)
+    }
+}
+
+/// Parses a position in the audio given in seconds or as `[HH:]MM:SS[.FF]`.
+fn parse_position(s: &str) -> std::result::Result<f64, String> {
+    let invalid = || format!("invalid position {s:?}, expected seconds or [HH:]MM:SS[.FF]");
+    let mut parts = s.rsplit(':');
+    let seconds: f64 = parts
+        .next()
+        .and_then(|seconds| seconds.parse().ok())
+        .filter(|seconds: &f64| seconds.is_finite() && *seconds >= 0.0)
+        .ok_or_else(invalid)?;
+    let mut position = seconds;
+    for (part, factor) in parts.zip([60., 3600.]) {
+        let value: u32 = part.parse().map_err(|_| invalid())?;
+        position += value as f64 * factor;
+    }
+    if s.matches(':').count() > 2 {
+        return Err(invalid());
+    }
+    Ok(position)
 }
 
 #[derive(Parser)]
@@ -37,20 +183,103 @@ pub fn main(
         output,
         model,
         language,
+        audio_stream,
+        start,
+        end,
+        reference,
+        resume,
         vad,
+        decoding,
     }: Opts,
-) {
-    let transcription = crate::whisper::transcribe(
-        std::fs::File::open(file).unwrap(),
+) -> Result<()> {
+    let mut checkpoint_path = output.clone().into_os_string();
+    checkpoint_path.push(".partial.jsonl");
+    let checkpoint_path = PathBuf::from(checkpoint_path);
+
+    let reference = reference
+        .map(|path| {
+            let source = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read reference {}", path.display()))?;
+            Reference::parse(source, ReferenceFormat::from_extension(&path))
+                .map(|reference| reference.text().to_string())
+        })
+        .transpose()?;
+
+    static CANCEL: AtomicBool = AtomicBool::new(false);
+    if let Err(error) = ctrlc::set_handler(|| {
+        if CANCEL.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+        eprintln!("\nStopping, press Ctrl-C again to exit immediately");
+    }) {
+        eprintln!("[warning] Failed to set up the Ctrl-C handler: {error}");
+    }
+
+    let options = TranscribeOptions {
         language,
         model,
-        vad.map(|x| SileroOptions {
+        silero: vad.map(|x| SileroOptions {
             path: x.path.expect("path should be set if vad is enabled"),
             threshold: x.speech_threshold,
             min_silence_seconds: x.min_silence_seconds,
             min_trim_silence_seconds: 2.0,
             speech_padding_seconds: x.padding_seconds,
         }),
+        decoding: decoding.into_options()?,
+        reference,
+        audio_stream,
+        range: (start.is_some() || end.is_some()).then(|| TimeRange {
+            start: start.unwrap_or(0.0),
+            end,
+        }),
+        checkpoint: Some(CheckpointOptions {
+            path: checkpoint_path.clone(),
+            resume,
+        }),
+    };
+
+    let transcription = if file == Path::new("-") {
+        whisper_aligner::transcribe(
+            std::io::stdin(),
+            "stdin".to_string(),
+            options,
+            Some(&CANCEL),
+        )?
+    } else {
+        let name = file
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let audio =
+            File::open(&file).with_context(|| format!("failed to open {}", file.display()))?;
+        // Pipes and other special files can't seek.
+        if audio.metadata().is_ok_and(|metadata| metadata.is_file()) {
+            whisper_aligner::transcribe_file(audio, name, options, Some(&CANCEL))?
+        } else {
+            whisper_aligner::transcribe(audio, name, options, Some(&CANCEL))?
+        }
+    };
+
+    let mut writer = BufWriter::new(
+        File::create(&output)
+            .with_context(|| format!("failed to create output {}", output.display()))?,
     );
-    serde_json::to_writer(std::fs::File::create(output).unwrap(), &transcription).unwrap();
+    serde_json::to_writer(&mut writer, &transcription)
+        .map_err(std::io::Error::from)
+        .and_then(|_| writer.flush())
+        .with_context(|| format!("failed to write output {}", output.display()))?;
+
+    if let Some(position) = transcription
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.interrupted_at)
+    {
+        eprintln!(
+            "[warning] The transcription was interrupted, only the first {position:.2}s were fully transcribed. Run the same command with --resume to continue."
+        );
+        return Err(Error::Interrupted);
+    }
+
+    std::fs::remove_file(&checkpoint_path)
+        .with_context(|| format!("failed to remove checkpoint {}", checkpoint_path.display()))
 }
@@ -0,0 +1,156 @@
+use std::fmt::Display;
+
+#[cfg(feature = "whisper")]
+use crate::ff::AVError;
+
+/// Error type used throughout the whole crate.
+///
+/// Every variant carries a description of what was being done when the error occurred, the
+/// underlying error (if any) is available through [`std::error::Error::source`].
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        context: String,
+        source: std::io::Error,
+    },
+    Json {
+        context: String,
+        source: serde_json::Error,
+    },
+    #[cfg(feature = "whisper")]
+    Av {
+        context: String,
+        source: AVError,
+    },
+    #[cfg(feature = "silero")]
+    Ort {
+        context: String,
+        source: ort::OrtError,
+    },
+    #[cfg(feature = "vibrato")]
+    Vibrato {
+        context: String,
+        source: vibrato::errors::VibratoError,
+    },
+    #[cfg(feature = "whisper")]
+    WhisperInit { model: std::path::PathBuf },
+    #[cfg(feature = "whisper")]
+    Whisper { context: String, code: i32 },
+    /// The transcription was stopped before it was complete.
+    #[cfg(feature = "whisper")]
+    Interrupted,
+    /// Some parameter or input file had an invalid value.
+    Invalid(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io { context, .. } | Error::Json { context, .. } => f.write_str(context),
+            #[cfg(feature = "whisper")]
+            Error::Av { context, .. } => f.write_str(context),
+            #[cfg(feature = "silero")]
+            Error::Ort { context, .. } => f.write_str(context),
+            #[cfg(feature = "vibrato")]
+            Error::Vibrato { context, .. } => f.write_str(context),
+            #[cfg(feature = "whisper")]
+            Error::WhisperInit { model } => {
+                write!(f, "failed to load whisper model from {}", model.display())
+            }
+            #[cfg(feature = "whisper")]
+            Error::Whisper { context, code } => write!(f, "{context}: whisper returned {code}"),
+            #[cfg(feature = "whisper")]
+            Error::Interrupted => f.write_str("transcription was interrupted"),
+            Error::Invalid(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Json { source, .. } => Some(source),
+            #[cfg(feature = "whisper")]
+            Error::Av { source, .. } => Some(source),
+            #[cfg(feature = "silero")]
+            Error::Ort { source, .. } => Some(source),
+            #[cfg(feature = "vibrato")]
+            Error::Vibrato { source, .. } => Some(source),
+            #[cfg(feature = "whisper")]
+            Error::WhisperInit { .. } | Error::Whisper { .. } | Error::Interrupted => None,
+            Error::Invalid(_) => None,
+        }
+    }
+}
+
+/// Errors that can be turned into an [`Error`] given some context.
+pub trait ContextSource {
+    fn with_context(self, context: String) -> Error;
+}
+
+impl ContextSource for std::io::Error {
+    fn with_context(self, context: String) -> Error {
+        Error::Io {
+            context,
+            source: self,
+        }
+    }
+}
+
+impl ContextSource for serde_json::Error {
+    fn with_context(self, context: String) -> Error {
+        Error::Json {
+            context,
+            source: self,
+        }
+    }
+}
+
+#[cfg(feature = "whisper")]
+impl ContextSource for AVError {
+    fn with_context(self, context: String) -> Error {
+        Error::Av {
+            context,
+            source: self,
+        }
+    }
+}
+
+#[cfg(feature = "silero")]
+impl ContextSource for ort::OrtError {
+    fn with_context(self, context: String) -> Error {
+        Error::Ort {
+            context,
+            source: self,
+        }
+    }
+}
+
+#[cfg(feature = "vibrato")]
+impl ContextSource for vibrato::errors::VibratoError {
+    fn with_context(self, context: String) -> Error {
+        Error::Vibrato {
+            context,
+            source: self,
+        }
+    }
+}
+
+/// Extension trait for attaching context to foreign errors.
+pub trait Context<T> {
+    fn context(self, context: impl Display) -> Result<T>;
+    fn with_context<C: Display>(self, context: impl FnOnce() -> C) -> Result<T>;
+}
+
+impl<T, E: ContextSource> Context<T> for Result<T, E> {
+    fn context(self, context: impl Display) -> Result<T> {
+        self.map_err(|error| error.with_context(context.to_string()))
+    }
+
+    fn with_context<C: Display>(self, context: impl FnOnce() -> C) -> Result<T> {
+        self.map_err(|error| error.with_context(context().to_string()))
+    }
+}
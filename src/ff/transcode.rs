@@ -1,6 +1,7 @@
 use std::ffi::{CStr, CString};
 
 use ffmpeg::*;
+use serde::{Deserialize, Serialize};
 
 use super::{AVError, AVI32Ext, AVResult};
 
@@ -22,9 +23,100 @@ unsafe fn codecctx_to_option_string(ctx: &AVCodecContext, plural: bool) -> AVRes
     .unwrap())
 }
 
-pub unsafe fn audio_demux_transcode_16khz_pcmf32le(
-    src: *mut AVIOContext,
-) -> AVResult<impl Iterator<Item = AVResult<*mut AVFrame>>> {
+/// Picks one of the audio streams of a file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamSelector {
+    /// Index among the audio streams, not among all streams of the file.
+    Index(usize),
+    /// Language tag of the stream, compared case-insensitively.
+    Language(String),
+    /// Part of the title of the stream, compared case-insensitively.
+    Title(String),
+}
+
+impl std::str::FromStr for StreamSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(language) = s.strip_prefix("lang:") {
+            Ok(Self::Language(language.to_string()))
+        } else if let Some(title) = s.strip_prefix("title:") {
+            Ok(Self::Title(title.to_string()))
+        } else {
+            s.parse().map(Self::Index).map_err(|_| {
+                format!("invalid audio stream {s:?}, expected an index, lang:<LANGUAGE> or title:<TITLE>")
+            })
+        }
+    }
+}
+
+impl std::fmt::Display for StreamSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Index(index) => write!(f, "{index}"),
+            Self::Language(language) => write!(f, "lang:{language}"),
+            Self::Title(title) => write!(f, "title:{title}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AudioStreamInfo {
+    /// Index among the audio streams, as used by [`StreamSelector::Index`].
+    pub index: usize,
+    /// Index among all streams of the file.
+    pub stream_index: usize,
+    pub codec: String,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub channels: i32,
+    pub sample_rate: i32,
+}
+
+impl AudioStreamInfo {
+    fn matches(&self, selector: &StreamSelector) -> bool {
+        match selector {
+            StreamSelector::Index(index) => self.index == *index,
+            StreamSelector::Language(language) => self
+                .language
+                .as_ref()
+                .is_some_and(|value| value.eq_ignore_ascii_case(language)),
+            StreamSelector::Title(title) => self
+                .title
+                .as_ref()
+                .is_some_and(|value| value.to_lowercase().contains(&title.to_lowercase())),
+        }
+    }
+}
+
+impl std::fmt::Display for AudioStreamInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "#{} (stream {}): {}, {} Hz, {} channels",
+            self.index, self.stream_index, self.codec, self.sample_rate, self.channels
+        )?;
+        if let Some(language) = self.language.as_ref() {
+            write!(f, ", language {language}")?;
+        }
+        if let Some(title) = self.title.as_ref() {
+            write!(f, ", title {title:?}")?;
+        }
+        Ok(())
+    }
+}
+
+unsafe fn metadata_value(stream: &AVStream, key: &CStr) -> Option<String> {
+    let entry = av_dict_get(stream.metadata, key.as_ptr(), std::ptr::null(), 0);
+    (!entry.is_null()).then(|| {
+        CStr::from_ptr((*entry).value)
+            .to_string_lossy()
+            .into_owned()
+    })
+}
+
+unsafe fn open_input(src: *mut AVIOContext) -> AVResult<*mut AVFormatContext> {
     let mut fmtctx = avformat_alloc_context();
     (*fmtctx).pb = src;
     avformat_open_input(
@@ -34,17 +126,95 @@ pub unsafe fn audio_demux_transcode_16khz_pcmf32le(
         std::ptr::null_mut(),
     )
     .av_void()?;
+    Ok(fmtctx)
+}
 
-    let streams = std::slice::from_raw_parts((*fmtctx).streams, (*fmtctx).nb_streams as usize);
-    let stream = streams
+unsafe fn audio_streams(fmtctx: *mut AVFormatContext) -> Vec<(AudioStreamInfo, *mut AVStream)> {
+    std::slice::from_raw_parts((*fmtctx).streams, (*fmtctx).nb_streams as usize)
         .iter()
-        .find(|s| (*(***s).codecpar).codec_type == AVMediaType::AVMEDIA_TYPE_AUDIO)
-        .map(|s| &**s)
-        .unwrap();
+        .copied()
+        .filter(|&stream| (*(*stream).codecpar).codec_type == AVMediaType::AVMEDIA_TYPE_AUDIO)
+        .enumerate()
+        .map(|(index, stream)| {
+            let codecpar = &*(*stream).codecpar;
+            let info = AudioStreamInfo {
+                index,
+                stream_index: (*stream).index as usize,
+                codec: CStr::from_ptr(avcodec_get_name(codecpar.codec_id))
+                    .to_string_lossy()
+                    .into_owned(),
+                language: metadata_value(&*stream, c"language"),
+                title: metadata_value(&*stream, c"title"),
+                channels: codecpar.ch_layout.nb_channels,
+                sample_rate: codecpar.sample_rate,
+            };
+            (info, stream)
+        })
+        .collect()
+}
+
+pub unsafe fn list_audio_streams(src: *mut AVIOContext) -> AVResult<Vec<AudioStreamInfo>> {
+    let mut fmtctx = open_input(src)?;
+    let streams = audio_streams(fmtctx)
+        .into_iter()
+        .map(|(info, _)| info)
+        .collect();
+    avformat_close_input(&mut fmtctx);
+    Ok(streams)
+}
+
+pub struct DecodedAudio<I> {
+    /// Duration of the whole input in seconds, if known.
+    pub duration: Option<f64>,
+    /// The stream which is being decoded.
+    pub stream: AudioStreamInfo,
+    pub frames: I,
+}
+
+/// Decodes the first audio stream matching `selector`, or the first audio stream if there is none.
+///
+/// Only the audio between `start` and `end` seconds is returned, the input is seeked to `start` if
+/// possible and decoding stops after `end`.
+pub unsafe fn audio_demux_transcode_16khz_pcmf32le(
+    src: *mut AVIOContext,
+    selector: Option<&StreamSelector>,
+    start: f64,
+    end: Option<f64>,
+) -> AVResult<DecodedAudio<impl Iterator<Item = AVResult<*mut AVFrame>>>> {
+    let fmtctx = open_input(src)?;
+    let duration = ((*fmtctx).duration != AV_NOPTS_VALUE)
+        .then(|| (*fmtctx).duration as f64 / AV_TIME_BASE as f64);
+
+    let (info, stream) = audio_streams(fmtctx)
+        .into_iter()
+        .find(|(info, _)| selector.is_none_or(|selector| info.matches(selector)))
+        .ok_or(AVError(AVERROR_STREAM_NOT_FOUND))?;
+    let stream = &*stream;
+    let stream_index = stream.index;
+    let trim = start > 0.0 || end.is_some();
+    let time_base = stream.time_base;
+    let seconds_per_tick = time_base.num as f64 / time_base.den as f64;
+    let start_pts = if stream.start_time == AV_NOPTS_VALUE {
+        0
+    } else {
+        stream.start_time
+    };
+
+    if start > 0.0 {
+        // Seeking lands on or before `start`, if it fails everything before it is decoded and
+        // dropped by atrim instead.
+        let _ = av_seek_frame(
+            fmtctx,
+            stream_index,
+            start_pts + (start / seconds_per_tick) as i64,
+            AVSEEK_FLAG_BACKWARD,
+        );
+    }
 
     let codec = avcodec_find_decoder((*stream.codecpar).codec_id);
     let codecctx = avcodec_alloc_context3(codec);
     avcodec_parameters_to_context(codecctx, stream.codecpar).av_void()?;
+    (*codecctx).pkt_timebase = time_base;
     avcodec_open2(codecctx, codec, std::ptr::null_mut()).av_void()?;
 
     let mut abufferctx = std::ptr::null_mut();
@@ -55,16 +225,44 @@ pub unsafe fn audio_demux_transcode_16khz_pcmf32le(
     let abuffersink = avfilter_get_by_name(c"abuffersink".as_ptr());
     let filter_graph = avfilter_graph_alloc();
 
+    let abuffer_options = CString::new(format!(
+        "{}:time_base={}/{}",
+        codecctx_to_option_string(&*codecctx, false)?
+            .to_str()
+            .unwrap(),
+        time_base.num,
+        time_base.den
+    ))
+    .unwrap();
     avfilter_graph_create_filter(
         &mut abufferctx,
         abuffer,
         std::ptr::null(),
-        codecctx_to_option_string(&*codecctx, false)?.as_ptr(),
+        abuffer_options.as_ptr(),
         std::ptr::null_mut(),
         filter_graph,
     )
     .av_void()?;
 
+    // Cuts the decoded audio to the requested range with sample precision.
+    let mut atrimctx = std::ptr::null_mut();
+    if trim {
+        let mut options = format!("start={start}");
+        if let Some(end) = end {
+            options += &format!(":end={end}");
+        }
+        let options = CString::new(options).unwrap();
+        avfilter_graph_create_filter(
+            &mut atrimctx,
+            avfilter_get_by_name(c"atrim".as_ptr()),
+            std::ptr::null(),
+            options.as_ptr(),
+            std::ptr::null_mut(),
+            filter_graph,
+        )
+        .av_void()?;
+    }
+
     avfilter_graph_create_filter(
         &mut aformatctx,
         aformat,
@@ -85,7 +283,12 @@ pub unsafe fn audio_demux_transcode_16khz_pcmf32le(
     )
     .av_void()?;
 
-    avfilter_link(abufferctx, 0, aformatctx, 0).av_void()?;
+    if trim {
+        avfilter_link(abufferctx, 0, atrimctx, 0).av_void()?;
+        avfilter_link(atrimctx, 0, aformatctx, 0).av_void()?;
+    } else {
+        avfilter_link(abufferctx, 0, aformatctx, 0).av_void()?;
+    }
     avfilter_link(aformatctx, 0, abuffersinkctx, 0).av_void()?;
 
     avfilter_graph_config(filter_graph, std::ptr::null_mut()).av_void()?;
@@ -100,7 +303,18 @@ pub unsafe fn audio_demux_transcode_16khz_pcmf32le(
                     Err(AVError(AVERROR_EOF)) => {
                         avcodec_send_packet(codecctx, std::ptr::null_mut()).av_void()
                     }
-                    other => other.and_then(|_| avcodec_send_packet(codecctx, packet).av_void()),
+                    other => {
+                        // Packets of the other streams are skipped.
+                        let result = other.and_then(|_| {
+                            if (*packet).stream_index == stream_index {
+                                avcodec_send_packet(codecctx, packet).av_void()
+                            } else {
+                                Ok(())
+                            }
+                        });
+                        av_packet_unref(packet);
+                        result
+                    }
                 } {
                     return Some(Err(e));
                 }
@@ -110,11 +324,29 @@ pub unsafe fn audio_demux_transcode_16khz_pcmf32le(
         };
     });
 
-    Ok(std::iter::from_fn(move || loop {
+    let frames = std::iter::from_fn(move || loop {
         match av_buffersink_get_frame(abuffersinkctx, frame).av_void() {
             Err(AVError(value)) if value == AVERROR(EAGAIN) => {
                 if let Err(e) = decoded_frames
                     .next()
+                    .map(|frame| {
+                        frame.map(|frame| {
+                            // Timestamps relative to the start of the stream, as atrim expects.
+                            let pts = (*frame).best_effort_timestamp;
+                            if pts == AV_NOPTS_VALUE {
+                                return frame;
+                            }
+                            (*frame).pts = pts - start_pts;
+                            let past_end = end.is_some_and(|end| {
+                                (pts - start_pts) as f64 * seconds_per_tick > end
+                            });
+                            if past_end {
+                                std::ptr::null_mut()
+                            } else {
+                                frame
+                            }
+                        })
+                    })
                     .unwrap_or(Ok(std::ptr::null_mut()))
                     .and_then(|frame| av_buffersrc_write_frame(abufferctx, frame).av_void())
                 {
@@ -124,5 +356,11 @@ pub unsafe fn audio_demux_transcode_16khz_pcmf32le(
             Err(AVError(AVERROR_EOF)) => return None,
             other => return Some(other.map(|_| frame)),
         }
-    }))
+    });
+
+    Ok(DecodedAudio {
+        duration,
+        stream: info,
+        frames,
+    })
 }
@@ -1,7 +1,7 @@
 use std::{
     borrow::Cow,
     ffi::{c_int, c_void, CStr},
-    io::{Read, Write},
+    io::{Read, Seek, SeekFrom, Write},
 };
 
 use ffmpeg::*;
@@ -67,10 +67,44 @@ unsafe extern "C" fn avio_read_impl<R: Read>(user: *mut c_void, buf: *mut u8, bu
     match reader.read(std::slice::from_raw_parts_mut(buf, buf_size as usize)) {
         Ok(0) => AVERROR_EOF,
         Ok(nread) => nread as i32,
-        Err(error) => match error.raw_os_error() {
-            Some(errno) => AVERROR(errno),
-            None => AVERROR_UNKNOWN,
-        },
+        Err(error) => io_error_to_av(error),
+    }
+}
+
+fn io_error_to_av(error: std::io::Error) -> i32 {
+    match error.raw_os_error() {
+        Some(errno) => AVERROR(errno),
+        None => AVERROR_UNKNOWN,
+    }
+}
+
+// Values of `whence` from stdio.h, they are the same everywhere.
+const SEEK_SET: c_int = 0;
+const SEEK_CUR: c_int = 1;
+const SEEK_END: c_int = 2;
+
+unsafe extern "C" fn avio_seek_impl<R: Seek>(user: *mut c_void, offset: i64, whence: c_int) -> i64 {
+    let reader = &mut *(user as *mut R);
+
+    let result = if whence & AVSEEK_SIZE != 0 {
+        // Size of the whole stream, without moving the position.
+        reader.stream_position().and_then(|position| {
+            let size = reader.seek(SeekFrom::End(0))?;
+            reader.seek(SeekFrom::Start(position))?;
+            Ok(size)
+        })
+    } else {
+        match whence & !AVSEEK_FORCE {
+            SEEK_SET if offset >= 0 => reader.seek(SeekFrom::Start(offset as u64)),
+            SEEK_CUR => reader.seek(SeekFrom::Current(offset)),
+            SEEK_END => reader.seek(SeekFrom::End(offset)),
+            _ => return AVERROR(EINVAL) as i64,
+        }
+    };
+
+    match result {
+        Ok(position) => position as i64,
+        Err(error) => io_error_to_av(error) as i64,
     }
 }
 
@@ -79,10 +113,7 @@ unsafe extern "C" fn avio_write_impl<W: Write>(user: *mut c_void, buf: *const u8
 
     match writer.write(std::slice::from_raw_parts(buf, buf_size as usize)) {
         Ok(nwritten) => nwritten as i32,
-        Err(error) => match error.raw_os_error() {
-            Some(errno) => AVERROR(errno),
-            None => AVERROR_UNKNOWN,
-        },
+        Err(error) => io_error_to_av(error),
     }
 }
 
@@ -100,6 +131,22 @@ pub unsafe fn read_to_avio<R: Read>(reader: Box<R>) -> *mut AVIOContext {
     )
 }
 
+/// Like [`read_to_avio`] but also lets ffmpeg seek, which some containers need, e.g. MP4 files
+/// with their index at the end.
+pub unsafe fn read_seek_to_avio<R: Read + Seek>(reader: Box<R>) -> *mut AVIOContext {
+    let buffer = av_malloc(2048) as *mut u8;
+
+    avio_alloc_context(
+        buffer,
+        2048,
+        0,
+        Box::leak(reader) as *mut _ as *mut c_void,
+        Some(avio_read_impl::<R>),
+        None,
+        Some(avio_seek_impl::<R>),
+    )
+}
+
 pub unsafe fn write_to_avio<W: Write>(writer: Box<W>) -> *mut AVIOContext {
     let buffer = av_malloc(2048) as *mut u8;
 
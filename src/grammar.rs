@@ -0,0 +1,117 @@
+//! Grammars which make whisper prefer to output a part of the reference text, word after word.
+
+use whisper_cpp_sys::{
+    whisper_grammar_element, whisper_gretype, whisper_gretype_WHISPER_GRETYPE_ALT,
+    whisper_gretype_WHISPER_GRETYPE_CHAR, whisper_gretype_WHISPER_GRETYPE_END,
+    whisper_gretype_WHISPER_GRETYPE_RULE_REF,
+};
+
+/// Whisper has to check every alternative of the start rule against every token, so the number of
+/// words is limited to keep that affordable.
+const MAX_WORDS: usize = 2000;
+
+fn element(type_: whisper_gretype, value: u32) -> whisper_grammar_element {
+    whisper_grammar_element { type_, value }
+}
+
+/// Scripts without spaces between words are split after every character instead.
+fn is_cjk(chr: char) -> bool {
+    matches!(
+        chr,
+        '\u{3040}'..='\u{30ff}' | '\u{3400}'..='\u{4dbf}' | '\u{4e00}'..='\u{9fff}' | '\u{f900}'..='\u{faff}'
+    )
+}
+
+/// Splits `text` into the units whisper may start or stop at, punctuation stays attached to the
+/// preceding word.
+pub(crate) fn words(text: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut current = String::new();
+    for chr in text.chars() {
+        if chr.is_whitespace()
+            || (chr.is_alphanumeric()
+                && current.chars().any(is_cjk)
+                && current.chars().any(char::is_alphanumeric))
+        {
+            words.extend((!current.is_empty()).then(|| std::mem::take(&mut current)));
+        }
+        if !chr.is_whitespace() {
+            current.push(chr);
+        }
+    }
+    words.extend((!current.is_empty()).then_some(current));
+    words
+}
+
+/// A grammar matching any run of consecutive words of a text, optionally separated by spaces.
+///
+/// Rule 0 starts at any of the words and rule 1 is an optional space. Rule `2 + 2 * i` matches the
+/// `i`th word, rule `3 + 2 * i` optionally continues with the next one.
+pub struct ReferenceGrammar {
+    rules: Vec<Vec<whisper_grammar_element>>,
+    pointers: Vec<*const whisper_grammar_element>,
+}
+
+impl ReferenceGrammar {
+    /// Returns `None` if `text` contains no words.
+    pub fn new(text: &str) -> Option<Self> {
+        let mut words = words(text);
+        words.truncate(MAX_WORDS);
+        if words.is_empty() {
+            return None;
+        }
+
+        let rule_ref = |rule: usize| element(whisper_gretype_WHISPER_GRETYPE_RULE_REF, rule as u32);
+        let word_rule = |i: usize| rule_ref(2 + 2 * i);
+        let alt = element(whisper_gretype_WHISPER_GRETYPE_ALT, 0);
+        let end = element(whisper_gretype_WHISPER_GRETYPE_END, 0);
+
+        let mut start = vec![];
+        for i in 0..words.len() {
+            if i != 0 {
+                start.push(alt);
+            }
+            start.push(word_rule(i));
+        }
+        start.push(end);
+
+        let space = vec![
+            element(whisper_gretype_WHISPER_GRETYPE_CHAR, ' ' as u32),
+            alt,
+            end,
+        ];
+
+        let mut rules = vec![start, space];
+        for (i, word) in words.iter().enumerate() {
+            let mut rule = vec![rule_ref(1)];
+            rule.extend(
+                word.chars()
+                    .map(|chr| element(whisper_gretype_WHISPER_GRETYPE_CHAR, chr as u32)),
+            );
+            rule.push(rule_ref(3 + 2 * i));
+            rule.push(end);
+            rules.push(rule);
+
+            let continuation = if i + 1 < words.len() {
+                vec![word_rule(i + 1), alt, end]
+            } else {
+                vec![end]
+            };
+            rules.push(continuation);
+        }
+
+        let pointers = rules.iter().map(|rule| rule.as_ptr()).collect();
+        Some(ReferenceGrammar { rules, pointers })
+    }
+
+    pub fn word_count(&self) -> usize {
+        (self.rules.len() - 2) / 2
+    }
+
+    /// Points the grammar parameters of whisper at this grammar, which has to outlive `params`.
+    pub fn apply(&mut self, params: &mut whisper_cpp_sys::whisper_full_params) {
+        params.grammar_rules = self.pointers.as_mut_ptr();
+        params.n_grammar_rules = self.pointers.len();
+        params.i_start_rule = 0;
+    }
+}
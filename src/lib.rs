@@ -0,0 +1,54 @@
+//! Whisper-assisted transcription alignment.
+//!
+//! The pipeline consists of two independent steps:
+//! 1. [`transcribe`] runs whisper on an audio file and produces a [`Transcription`] (requires the
+//!    `whisper` feature which links whisper.cpp and ffmpeg).
+//! 2. [`align_transcription`] aligns a correct reference text with a [`Transcription`] using a
+//!    [`Tokenizer`] and returns the reference lines with their timing, these can then be written
+//!    out as subtitles with [`OutputFormat::write`].
+
+pub mod align;
+#[cfg(feature = "whisper")]
+mod boost;
+#[cfg(feature = "whisper")]
+pub mod checkpoint;
+pub mod error;
+#[cfg(feature = "whisper")]
+mod ff;
+#[cfg(feature = "whisper")]
+mod grammar;
+pub mod markup;
+pub mod output;
+#[cfg(feature = "whisper")]
+mod progress;
+#[cfg(feature = "whisper")]
+mod prompt;
+pub mod reference;
+#[cfg(feature = "whisper")]
+mod sections;
+#[cfg(feature = "silero")]
+pub mod silero;
+pub mod timing;
+pub mod tokenize;
+pub mod transcription;
+#[cfg(feature = "whisper")]
+pub mod whisper;
+
+pub use align::AlignStrategy;
+#[cfg(feature = "whisper")]
+pub use checkpoint::CheckpointOptions;
+pub use error::{Error, Result};
+#[cfg(feature = "whisper")]
+pub use ff::{AVError, AudioStreamInfo, StreamSelector};
+pub use markup::{Markup, MarkupRule, MarkupRules, Restored};
+pub use output::{OutputFormat, WriteOptions};
+pub use reference::{Reference, ReferenceFormat, Subtitles};
+pub use timing::{align_transcription, AlignOptions, Alignment, TimedLine, TimedWord};
+#[cfg(feature = "vibrato")]
+pub use tokenize::VibratoTokenizer;
+pub use tokenize::{Tokenizer, WhitespaceTokenizer};
+pub use transcription::{DecodingOptions, Strategy, TimeRange, Token, Transcription};
+#[cfg(feature = "whisper")]
+pub use whisper::{
+    list_audio_streams, transcribe, transcribe_file, SileroOptions, TranscribeOptions,
+};
@@ -1,11 +1,21 @@
+use std::process::ExitCode;
+
 use clap::Parser;
 
-mod align;
 mod cli;
-mod ff;
-mod silero;
-mod whisper;
 
-fn main() {
-    cli::main(cli::Opts::parse())
+fn main() -> ExitCode {
+    match cli::main(cli::Opts::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            let mut message = error.to_string();
+            let mut source = std::error::Error::source(&error);
+            while let Some(error) = source {
+                message += &format!(": {error}");
+                source = error.source();
+            }
+            eprintln!("error: {message}");
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,339 @@
+//! Annotations in the reference which aren't spoken, like speaker labels, stage directions or sound
+//! cues. They are cut out of the text before aligning so they can't compete with spoken words, and
+//! put back into the timed lines afterwards.
+
+use std::{ops::Range, str::FromStr};
+
+use regex::Regex;
+
+use crate::timing::TimedLine;
+
+/// Describes spans of a line which aren't spoken.
+#[derive(Clone, Debug)]
+pub enum MarkupRule {
+    /// Text between a pair of brackets including the brackets, nested pairs are skipped over.
+    Brackets(char, char),
+    /// Every match of a regular expression, applied to each line on its own.
+    Regex(Regex),
+}
+
+impl MarkupRule {
+    fn find(&self, line: &str, spans: &mut Vec<Range<usize>>) {
+        match self {
+            Self::Brackets(open, close) => {
+                let mut start = None;
+                let mut depth = 0;
+                for (i, chr) in line.char_indices() {
+                    if chr == *open {
+                        start = start.or(Some(i));
+                        depth += 1;
+                    } else if chr == *close && depth > 0 {
+                        depth -= 1;
+                        if depth == 0 {
+                            spans.push(start.take().unwrap()..i + chr.len_utf8());
+                        }
+                    }
+                }
+            }
+            Self::Regex(regex) => spans.extend(
+                regex
+                    .find_iter(line)
+                    .map(|found| found.range())
+                    .filter(|range| !range.is_empty()),
+            ),
+        }
+    }
+}
+
+impl FromStr for MarkupRule {
+    type Err = String;
+
+    /// Two different characters which aren't alphanumeric are a pair of brackets, anything else a
+    /// regular expression.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let is_bracket = |chr: char| !chr.is_alphanumeric() && !chr.is_whitespace();
+        if let [open, close] = s.chars().collect::<Vec<_>>()[..] {
+            if open != close && is_bracket(open) && is_bracket(close) {
+                return Ok(Self::Brackets(open, close));
+            }
+        }
+
+        Regex::new(s)
+            .map(Self::Regex)
+            .map_err(|e| format!("invalid markup rule {s:?}: {e}"))
+    }
+}
+
+/// Rules for finding the annotations of a reference.
+#[derive(Clone, Debug, Default)]
+pub struct MarkupRules {
+    pub rules: Vec<MarkupRule>,
+    /// Matches the speaker label of a line, its first capture group is the name of the speaker.
+    /// Without a capture group the whole match is used, minus surrounding colons and whitespace.
+    pub speaker_label: Option<Regex>,
+}
+
+/// A line of the reference containing annotations.
+struct LineMarkup {
+    original: String,
+    /// The line without annotations.
+    clean: String,
+    /// Byte offset in `original` of every byte of `clean`.
+    clean_offsets: Vec<usize>,
+    /// Range of the speaker label in `original` and the name of the speaker.
+    speaker: Option<(Range<usize>, String)>,
+    /// Whether there's nothing but annotations in the line.
+    only_markup: bool,
+}
+
+/// A reference with its annotations cut out, see [`MarkupRules::apply`].
+pub struct Markup {
+    text: String,
+    /// Only lines containing annotations are stored.
+    lines: Vec<Option<LineMarkup>>,
+}
+
+/// Result of [`Markup::restore`].
+pub struct Restored {
+    pub lines: Vec<TimedLine>,
+    /// Text of the lines containing only annotations which had no gap between their neighbours to
+    /// be timed in.
+    pub skipped: Vec<String>,
+}
+
+impl MarkupRules {
+    /// Cuts the annotations out of every line of `text`, the number of lines stays the same.
+    pub fn apply(&self, text: &str) -> Markup {
+        let mut clean_text = String::with_capacity(text.len());
+        let mut lines = vec![];
+        for (i, line) in text.split('\n').enumerate() {
+            if i != 0 {
+                clean_text.push('\n');
+            }
+
+            let mut spans = vec![];
+            let speaker = self.speaker_label.as_ref().and_then(|regex| {
+                let captures = regex.captures(line)?;
+                let label = captures.get(0).unwrap();
+                let name = captures.get(1).map_or_else(
+                    || {
+                        label
+                            .as_str()
+                            .trim_matches(|chr: char| chr == ':' || chr.is_whitespace())
+                    },
+                    |name| name.as_str().trim(),
+                );
+                (!label.is_empty()).then(|| (label.range(), name.to_string()))
+            });
+            spans.extend(speaker.as_ref().map(|(range, _)| range.clone()));
+            for rule in self.rules.iter() {
+                rule.find(line, &mut spans);
+            }
+            if spans.is_empty() {
+                clean_text += line;
+                lines.push(None);
+                continue;
+            }
+
+            // Overlapping spans are cut out together.
+            spans.sort_by_key(|span| span.start);
+            spans.push(line.len()..line.len());
+            let mut clean = String::new();
+            let mut clean_offsets = vec![];
+            let mut position = 0;
+            for span in spans {
+                if span.start > position {
+                    clean += &line[position..span.start];
+                    clean_offsets.extend(position..span.start);
+                }
+                position = position.max(span.end);
+            }
+            clean_text += &clean;
+
+            lines.push(Some(LineMarkup {
+                original: line.to_string(),
+                only_markup: clean.trim().is_empty(),
+                clean,
+                clean_offsets,
+                speaker,
+            }));
+        }
+
+        Markup {
+            text: clean_text,
+            lines,
+        }
+    }
+}
+
+impl Markup {
+    /// The reference without annotations, to be aligned instead of the original.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Puts the annotations back into `lines`, which have to be timed from [`Markup::text`].
+    ///
+    /// With `voice_tags` speaker labels are removed from the text and stored as the speaker of the
+    /// line instead. Lines containing only annotations are timed to fill the gap between their
+    /// neighbours.
+    pub fn restore(&self, lines: Vec<TimedLine>, voice_tags: bool) -> Restored {
+        let mut result: Vec<TimedLine> = lines
+            .into_iter()
+            .filter_map(|line| match self.lines.get(line.line) {
+                // Whatever was left of the line is timed below.
+                Some(Some(markup)) if markup.only_markup => None,
+                Some(Some(markup)) => Some(markup.restore(line, voice_tags)),
+                _ => Some(line),
+            })
+            .collect();
+
+        let mut skipped = vec![];
+        for (index, markup) in self.lines.iter().enumerate() {
+            let Some(markup) = markup.as_ref().filter(|markup| markup.only_markup) else {
+                continue;
+            };
+            let next = result.partition_point(|line| line.line < index);
+            let text = markup.original.trim().to_string();
+            match (next.checked_sub(1).map(|i| &result[i]), result.get(next)) {
+                (Some(previous), Some(next_line)) if next_line.start > previous.end => {
+                    let line = TimedLine {
+                        start: previous.end,
+                        end: next_line.start,
+                        text,
+                        words: vec![],
+                        line: index,
+                        speaker: None,
+                    };
+                    result.insert(next, line);
+                }
+                _ => skipped.push(text),
+            }
+        }
+
+        Restored {
+            lines: result,
+            skipped,
+        }
+    }
+}
+
+impl LineMarkup {
+    fn restore(&self, mut line: TimedLine, voice_tags: bool) -> TimedLine {
+        let (text_start, speaker) = match self.speaker.as_ref() {
+            Some((range, name)) if voice_tags => (range.end, Some(name.clone())),
+            _ => (0, None),
+        };
+        let text = &self.original[text_start..];
+        let text_start = text_start + text.len() - text.trim_start().len();
+        let text = text.trim();
+
+        // The words are looked up in the line without annotations and their offsets mapped back to
+        // the original line, since the tokenizer may have dropped whitespace from the timed text.
+        let clean = &self.clean;
+        let mut cursor = 0;
+        let mut last_offset = 0;
+        for word in line.words.iter_mut() {
+            if let Some(found) = clean[cursor..].find(&word.text) {
+                cursor += found + word.text.len();
+                let offset = self.clean_offsets[cursor - word.text.len()];
+                last_offset = offset.saturating_sub(text_start).min(text.len());
+            }
+            word.offset = last_offset;
+        }
+
+        line.text = text.to_string();
+        line.speaker = speaker;
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        reference::{Reference, ReferenceFormat},
+        timing::{align_transcription, AlignOptions},
+        transcription::{Token, Transcription, TRANSCRIPTION_VERSION},
+        WhitespaceTokenizer,
+    };
+
+    fn transcription(words: &[(&str, i64, i64)]) -> Transcription {
+        let tokens = words
+            .iter()
+            .map(|&(text, start, end)| Token {
+                probability: 1.0,
+                start,
+                end,
+                text: text.to_string(),
+                dtw: None,
+            })
+            .collect();
+        Transcription {
+            version: TRANSCRIPTION_VERSION,
+            language: "en".to_string(),
+            language_probability: None,
+            metadata: None,
+            range: None,
+            segments: vec![tokens],
+        }
+    }
+
+    fn brackets() -> MarkupRules {
+        MarkupRules {
+            rules: vec![MarkupRule::Brackets('[', ']')],
+            speaker_label: None,
+        }
+    }
+
+    fn align(reference: &str) -> Vec<TimedLine> {
+        let transcription = transcription(&[
+            (" Hello", 120, 150),
+            (" world", 150, 180),
+            (" Bye", 310, 350),
+            (" now", 350, 390),
+        ]);
+        let markup = brackets().apply(reference);
+        let alignment = align_transcription(
+            &transcription,
+            markup.text(),
+            &mut WhitespaceTokenizer,
+            &AlignOptions::default(),
+        );
+        markup.restore(alignment.lines, false).lines
+    }
+
+    #[test]
+    fn markup_only_first_line() {
+        let lines = align("[Music]\nHello world\nBye now");
+        let lines = lines
+            .iter()
+            .map(|line| (line.line, line.text.as_str(), line.start, line.end))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [(1, "Hello world", 125, 180), (2, "Bye now", 320, 390)]
+        );
+    }
+
+    #[test]
+    fn markup_only_first_cue() {
+        let srt = "1\n00:00:00,000 --> 00:00:01,000\n[Music]\n\n\
+                   2\n00:00:01,000 --> 00:00:02,000\nHello world\n\n\
+                   3\n00:00:03,000 --> 00:00:04,000\nBye now\n";
+        let reference = Reference::parse(srt.to_string(), ReferenceFormat::Srt).unwrap();
+        let lines = align(reference.text());
+        let Reference::Subtitles(subtitles) = reference else {
+            unreachable!()
+        };
+
+        let mut output = vec![];
+        subtitles.write_retimed(&lines, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "1\n00:00:00,000 --> 00:00:01,000\n[Music]\n\n\
+             2\n00:00:01,250 --> 00:00:01,800\nHello world\n\n\
+             3\n00:00:03,200 --> 00:00:03,900\nBye now\n"
+        );
+    }
+}
@@ -0,0 +1,450 @@
+use std::{io::Write, path::Path, str::FromStr};
+
+use serde::Serialize;
+
+use crate::timing::TimedLine;
+
+/// Subtitle format that [`TimedLine`]s can be written as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Vtt,
+    Srt,
+    Ass,
+    Lrc,
+    Json,
+}
+
+impl OutputFormat {
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+
+    pub fn write<'a>(
+        self,
+        options: &WriteOptions,
+        lines: impl IntoIterator<Item = &'a TimedLine>,
+        output: impl Write,
+    ) -> std::io::Result<()> {
+        match self {
+            Self::Vtt => timed_lines_to_vtt(options.language, options.karaoke, lines, output),
+            Self::Srt => timed_lines_to_srt(lines, output),
+            Self::Ass => timed_lines_to_ass(options.ass_template, lines, output),
+            Self::Lrc => timed_lines_to_lrc(options.lrc_headers, options.karaoke, lines, output),
+            Self::Json => timed_lines_to_json(options.language, lines, output),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "vtt" => Ok(Self::Vtt),
+            "srt" => Ok(Self::Srt),
+            "ass" => Ok(Self::Ass),
+            "lrc" => Ok(Self::Lrc),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "unknown output format {s:?}, expected one of vtt, srt, ass, lrc or json"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WriteOptions<'a> {
+    pub language: &'a str,
+    /// Script whose header and styles are copied into ASS output.
+    pub ass_template: Option<&'a str>,
+    /// Header tags like `[ar:Artist]` written at the top of LRC output.
+    pub lrc_headers: &'a [String],
+    /// Add inline timestamps before every word of WebVTT cues and LRC lines.
+    pub karaoke: bool,
+}
+
+/// Formats a timestamp in tens of milliseconds as `HH:MM:SS<separator>mmm`.
+pub(crate) fn format_timestamp(ts: i64, separator: char) -> String {
+    let ms = ts * 10;
+    let s = ms / 1000;
+    let min = s / 60;
+    let h = min / 60;
+    format!(
+        "{:02}:{:02}:{:02}{separator}{:<03}",
+        h,
+        min % 60,
+        s % 60,
+        ms % 1000
+    )
+}
+
+/// Formats a timestamp in tens of milliseconds as `H:MM:SS.cc`, like ASS does.
+pub(crate) fn format_ass_timestamp(ts: i64) -> String {
+    let s = ts / 100;
+    let min = s / 60;
+    let h = min / 60;
+    format!("{}:{:02}:{:02}.{:02}", h, min % 60, s % 60, ts % 100)
+}
+
+fn timed_lines_to_vtt<'a>(
+    language: &str,
+    karaoke: bool,
+    lines: impl IntoIterator<Item = &'a TimedLine>,
+    mut output: impl Write,
+) -> std::io::Result<()> {
+    let vtt_ts = |ts: i64| format_timestamp(ts, '.');
+
+    writeln!(output, "WEBVTT")?;
+    writeln!(output, "Kind: captions")?;
+    writeln!(output, "Languagee: {language}")?;
+    for line in lines.into_iter() {
+        writeln!(output)?;
+        writeln!(output, "{} --> {}", vtt_ts(line.start), vtt_ts(line.end))?;
+        if let Some(speaker) = line.speaker.as_ref() {
+            write!(output, "<v {speaker}>")?;
+        }
+        if karaoke {
+            // Timestamp tags have to be strictly increasing and inside the cue, words that would
+            // break this are just merged into the previous one.
+            let mut text = String::new();
+            let mut last_offset = 0;
+            let mut last_ts = line.start;
+            for word in line.words.iter() {
+                if word.start > last_ts && word.start < line.end {
+                    text += &line.text[last_offset..word.offset];
+                    text += &format!("<{}>", vtt_ts(word.start));
+                    last_offset = word.offset;
+                    last_ts = word.start;
+                }
+            }
+            text += &line.text[last_offset..];
+            writeln!(output, "{text}")?;
+        } else {
+            writeln!(output, "{}", line.text)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// `Speaker: ` in front of the text of lines with a speaker, for formats which have no place for it.
+fn speaker_prefix(line: &TimedLine) -> String {
+    line.speaker
+        .as_ref()
+        .map_or_else(String::new, |speaker| format!("{speaker}: "))
+}
+
+fn timed_lines_to_srt<'a>(
+    lines: impl IntoIterator<Item = &'a TimedLine>,
+    mut output: impl Write,
+) -> std::io::Result<()> {
+    let srt_ts = |ts: i64| format_timestamp(ts, ',');
+
+    for (i, line) in lines.into_iter().enumerate() {
+        writeln!(output, "{}", i + 1)?;
+        writeln!(output, "{} --> {}", srt_ts(line.start), srt_ts(line.end))?;
+        writeln!(output, "{}{}", speaker_prefix(line), line.text)?;
+        writeln!(output)?;
+    }
+
+    Ok(())
+}
+
+const ASS_DEFAULT_SCRIPT_INFO: &str = "[Script Info]
+; Script generated by whisper-aligner
+ScriptType: v4.00+
+WrapStyle: 0
+ScaledBorderAndShadow: yes
+PlayResX: 1920
+PlayResY: 1080";
+
+const ASS_DEFAULT_STYLES: &str = "[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, \
+Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, \
+Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: Default,Arial,72,&H00FFFFFF,&H000000FF,&H00000000,&H00000000,0,0,0,0,100,100,0,0,1,3,0,2,60,60,45,1";
+
+/// Names of the styles defined in an ASS script.
+fn ass_styles(template: &str) -> Vec<&str> {
+    template
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("Style:"))
+        .map(|definition| definition.split(',').next().unwrap().trim())
+        .collect()
+}
+
+/// Style of the dialogue lines of ASS output using `template`, its `Default` style or else its
+/// first one. Without any styles the default ones are added to the output.
+pub fn ass_template_style(template: &str) -> &str {
+    let styles = ass_styles(template);
+    if styles.is_empty() || styles.contains(&"Default") {
+        "Default"
+    } else {
+        styles[0]
+    }
+}
+
+/// Writes an ASS script, everything except the `[Events]` section of `template` is copied
+/// verbatim into the output.
+fn timed_lines_to_ass<'a>(
+    template: Option<&str>,
+    lines: impl IntoIterator<Item = &'a TimedLine>,
+    mut output: impl Write,
+) -> std::io::Result<()> {
+    let mut header = String::new();
+    if let Some(template) = template {
+        let mut in_events = false;
+        for line in template.trim_start_matches('\u{feff}').lines() {
+            let trimmed = line.trim();
+            if trimmed.starts_with('[') && trimmed.ends_with(']') {
+                in_events = trimmed.eq_ignore_ascii_case("[Events]");
+            }
+            if in_events {
+                continue;
+            }
+
+            header += line;
+            header += "\n";
+        }
+    } else {
+        header += ASS_DEFAULT_SCRIPT_INFO;
+    }
+
+    if ass_styles(&header).is_empty() {
+        header = header.trim_end().to_string() + "\n\n" + ASS_DEFAULT_STYLES;
+    }
+    let style = ass_template_style(&header);
+
+    writeln!(output, "{}", header.trim_end())?;
+    writeln!(output)?;
+    writeln!(output, "[Events]")?;
+    writeln!(
+        output,
+        "Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text"
+    )?;
+    for line in lines.into_iter() {
+        // Fields are separated by commas, Aegisub replaces them with semicolons as well.
+        let name = line
+            .speaker
+            .as_deref()
+            .unwrap_or_default()
+            .replace(',', ";")
+            .replace(['\r', '\n'], " ");
+        writeln!(
+            output,
+            "Dialogue: 0,{},{},{style},{name},0,0,0,,{}",
+            format_ass_timestamp(line.start),
+            format_ass_timestamp(line.end),
+            line.text.replace('\n', "\\N")
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Writes LRC lyrics, with `karaoke` every word gets a timestamp of enhanced LRC and the end of the
+/// line is marked by a final one.
+fn timed_lines_to_lrc<'a>(
+    headers: &[String],
+    karaoke: bool,
+    lines: impl IntoIterator<Item = &'a TimedLine>,
+    mut output: impl Write,
+) -> std::io::Result<()> {
+    // LRC only has minutes, which may exceed 59.
+    let lrc_ts = |ts: i64| format!("{:02}:{:02}.{:02}", ts / 6000, ts / 100 % 60, ts % 100);
+
+    for header in headers {
+        writeln!(output, "{header}")?;
+    }
+    for line in lines.into_iter() {
+        let mut text = speaker_prefix(line);
+        if karaoke {
+            let mut last_offset = 0;
+            let mut last_ts = line.start;
+            for word in line.words.iter() {
+                if word.start >= last_ts {
+                    text += &line.text[last_offset..word.offset];
+                    text += &format!("<{}>", lrc_ts(word.start));
+                    last_offset = word.offset;
+                    last_ts = word.start;
+                }
+            }
+            text += &line.text[last_offset..];
+            text += &format!("<{}>", lrc_ts(line.end.max(last_ts)));
+        } else {
+            text += &line.text;
+        }
+        writeln!(
+            output,
+            "[{}]{}",
+            lrc_ts(line.start),
+            text.replace('\n', " ")
+        )?;
+    }
+
+    Ok(())
+}
+
+fn timed_lines_to_json<'a>(
+    language: &str,
+    lines: impl IntoIterator<Item = &'a TimedLine>,
+    output: impl Write,
+) -> std::io::Result<()> {
+    #[derive(Serialize)]
+    struct Output<'a> {
+        language: &'a str,
+        lines: Vec<&'a TimedLine>,
+    }
+
+    serde_json::to_writer(
+        output,
+        &Output {
+            language,
+            lines: lines.into_iter().collect(),
+        },
+    )
+    .map_err(std::io::Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timing::TimedWord;
+
+    /// A line made of `words` separated by single spaces.
+    fn line(start: i64, end: i64, words: &[(&str, i64, i64)]) -> TimedLine {
+        let mut text = String::new();
+        let mut timed_words = vec![];
+        for &(word, start, end) in words {
+            if !text.is_empty() {
+                text.push(' ');
+            }
+            timed_words.push(TimedWord {
+                start,
+                end,
+                offset: text.len(),
+                text: word.to_string(),
+            });
+            text += word;
+        }
+        TimedLine {
+            start,
+            end,
+            text,
+            words: timed_words,
+            line: 0,
+            speaker: None,
+        }
+    }
+
+    fn lrc(headers: &[String], lines: &[TimedLine]) -> String {
+        let options = WriteOptions {
+            language: "en",
+            ass_template: None,
+            lrc_headers: headers,
+            karaoke: true,
+        };
+        let mut output = vec![];
+        OutputFormat::Lrc
+            .write(&options, lines, &mut output)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn enhanced_lrc() {
+        let headers = ["[ar:Artist]".to_string()];
+        let lines = [line(
+            100,
+            300,
+            &[("Hello", 100, 150), ("big", 150, 200), ("world", 210, 290)],
+        )];
+        assert_eq!(
+            lrc(&headers, &lines),
+            "[ar:Artist]\n[00:01.00]<00:01.00>Hello <00:01.50>big <00:02.10>world<00:03.00>\n"
+        );
+    }
+
+    #[test]
+    fn enhanced_lrc_monotonic() {
+        let lines = [
+            line(
+                6000,
+                6100,
+                &[
+                    ("one", 5990, 6010),
+                    ("two", 6050, 6060),
+                    ("three", 6020, 6030),
+                    ("four", 6080, 6200),
+                ],
+            ),
+            line(6150, 6300, &[("five", 6150, 6300)]),
+        ];
+        let output = lrc(&[], &lines);
+        assert_eq!(
+            output,
+            "[01:00.00]one <01:00.50>two three <01:00.80>four<01:01.00>\n\
+             [01:01.50]<01:01.50>five<01:03.00>\n"
+        );
+
+        for line in output.lines() {
+            let times = line
+                .split(['[', '<'])
+                .skip(1)
+                .map(|tag| tag.split([']', '>']).next().unwrap())
+                .collect::<Vec<_>>();
+            assert!(times.windows(2).all(|pair| pair[0] <= pair[1]), "{line}");
+        }
+    }
+
+    #[test]
+    fn ass_speaker_with_comma() {
+        let mut line = line(100, 200, &[("Hi", 100, 200)]);
+        line.speaker = Some("Alice, Bob".to_string());
+        let options = WriteOptions {
+            language: "en",
+            ass_template: None,
+            lrc_headers: &[],
+            karaoke: false,
+        };
+        let mut output = vec![];
+        OutputFormat::Ass
+            .write(&options, [&line], &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(
+            output.ends_with("Dialogue: 0,0:00:01.00,0:00:02.00,Default,Alice; Bob,0,0,0,,Hi\n"),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn speaker_in_srt_and_lrc() {
+        let mut line = line(100, 200, &[("Hi", 100, 150), ("there", 150, 200)]);
+        line.speaker = Some("Alice".to_string());
+        let write = |format: OutputFormat, karaoke| {
+            let options = WriteOptions {
+                language: "en",
+                ass_template: None,
+                lrc_headers: &[],
+                karaoke,
+            };
+            let mut output = vec![];
+            format.write(&options, [&line], &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(
+            write(OutputFormat::Srt, false),
+            "1\n00:00:01,000 --> 00:00:02,000\nAlice: Hi there\n\n"
+        );
+        assert_eq!(
+            write(OutputFormat::Lrc, false),
+            "[00:01.00]Alice: Hi there\n"
+        );
+        assert_eq!(
+            write(OutputFormat::Lrc, true),
+            "[00:01.00]Alice: <00:01.00>Hi <00:01.50>there<00:02.00>\n"
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Progress reporting for transcription, drawn as a single line on stderr which is redrawn in place.
+
+use std::{
+    io::{IsTerminal, Write},
+    time::{Duration, Instant},
+};
+
+fn format_seconds(seconds: f64) -> String {
+    let seconds = seconds.max(0.0) as u64;
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Keeps track of how far decoding, VAD and whisper got, all positions are in seconds of audio.
+pub struct Progress {
+    enabled: bool,
+    started: Instant,
+    last_draw: Option<Instant>,
+    duration: Option<f64>,
+    decoded: f64,
+    transcribed: f64,
+    /// Start, end and percentage of the section whisper is working on.
+    section: Option<(f64, f64, i32)>,
+}
+
+impl Progress {
+    /// Progress is only drawn if stderr is a terminal.
+    pub fn new(duration: Option<f64>) -> Self {
+        Progress {
+            enabled: std::io::stderr().is_terminal(),
+            started: Instant::now(),
+            last_draw: None,
+            duration,
+            decoded: 0.0,
+            transcribed: 0.0,
+            section: None,
+        }
+    }
+
+    pub fn decoded(&mut self, position: f64) {
+        self.decoded = position;
+        self.draw(false);
+    }
+
+    pub fn section_started(&mut self, start: f64, end: f64) {
+        self.section = Some((start, end, 0));
+        self.draw(true);
+    }
+
+    pub fn section_progress(&mut self, percentage: i32) {
+        if let Some((_, _, current)) = self.section.as_mut() {
+            *current = percentage;
+        }
+        self.draw(false);
+    }
+
+    pub fn section_finished(&mut self, end: f64) {
+        self.section = None;
+        self.transcribed = end;
+        self.draw(true);
+    }
+
+    /// Position up to which whisper is done, including the part of the current section.
+    fn position(&self) -> f64 {
+        match self.section {
+            Some((start, end, percentage)) => start + (end - start) * percentage as f64 / 100.,
+            None => self.transcribed,
+        }
+    }
+
+    fn draw(&mut self, force: bool) {
+        if !self.enabled
+            || (!force
+                && self
+                    .last_draw
+                    .is_some_and(|last| last.elapsed() < Duration::from_millis(200)))
+        {
+            return;
+        }
+        self.last_draw = Some(Instant::now());
+
+        let position = self.position();
+        let mut line = format!(
+            "decoded {} | transcribed {}",
+            format_seconds(self.decoded),
+            format_seconds(position)
+        );
+        if let Some(duration) = self.duration.filter(|duration| *duration > 0.0) {
+            let fraction = (position / duration).clamp(0.0, 1.0);
+            line += &format!(" of {} ({:.1}%)", format_seconds(duration), fraction * 100.);
+            if fraction > 0.0 {
+                let elapsed = self.started.elapsed().as_secs_f64();
+                line += &format!(
+                    " | ETA {}",
+                    format_seconds(elapsed / fraction * (1.0 - fraction))
+                );
+            }
+        }
+        if let Some((start, end, percentage)) = self.section {
+            line += &format!(
+                " | section {}-{} {percentage}%",
+                format_seconds(start),
+                format_seconds(end)
+            );
+        }
+
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(stderr, "\r\x1b[K{line}");
+        let _ = stderr.flush();
+    }
+
+    /// Moves past the progress line so that it isn't overwritten.
+    pub fn finish(&mut self) {
+        if self.last_draw.is_some() {
+            self.draw(true);
+            eprintln!();
+        }
+    }
+}
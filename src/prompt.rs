@@ -0,0 +1,163 @@
+//! Picks the part of the reference text which is most likely spoken in a section of audio, so that
+//! it can be given to whisper as its prompt.
+
+use std::collections::HashMap;
+
+use crate::align::katakana2hiragana;
+
+/// Length of the character n-grams used to find where a transcribed section ends in the reference.
+const NGRAM_CHARS: usize = 6;
+/// Only this many characters at the end of a section are matched against the reference.
+const MATCHED_TAIL_CHARS: usize = 300;
+/// N-grams occurring more often than this in the reference say little about the position.
+const MAX_NGRAM_OCCURRENCES: usize = 8;
+/// A position in the reference needs at least this many matching n-grams to be trusted.
+const MIN_VOTES: usize = 4;
+/// Whisper only looks at a few hundred prompt tokens, this is comfortably more than that.
+const MAX_PROMPT_CHARS: usize = 1000;
+/// How many characters the text expected in a section extends beyond the estimate on both sides.
+const EXPECTED_MARGIN_CHARS: usize = 300;
+
+fn normalize(text: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+    text.char_indices()
+        .filter(|(_, chr)| chr.is_alphanumeric())
+        .flat_map(|(i, chr)| {
+            chr.to_lowercase()
+                .map(move |chr| (i, katakana2hiragana(chr)))
+        })
+}
+
+/// Estimates which part of the reference is spoken at a position of the audio.
+///
+/// Without anything to go on the reference is assumed to be spread evenly over the audio, once a
+/// section was transcribed its end is looked up in the reference and the estimate continues from
+/// there at the speaking rate observed so far.
+pub struct ReferencePrompts {
+    text: String,
+    /// Byte offsets of the normalized characters of `text`.
+    offsets: Vec<usize>,
+    /// Positions of every n-gram of normalized characters.
+    ngrams: HashMap<String, Vec<usize>>,
+    /// Duration of the audio in seconds.
+    duration: Option<f64>,
+    /// Position in the audio and index of the normalized character spoken there, as found by the
+    /// last successful match.
+    anchor: Option<(f64, usize)>,
+}
+
+impl ReferencePrompts {
+    pub fn new(text: String, duration: Option<f64>) -> Self {
+        let (offsets, chars): (Vec<_>, Vec<_>) = normalize(&text).unzip();
+        let mut ngrams: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, window) in chars.windows(NGRAM_CHARS).enumerate() {
+            ngrams
+                .entry(window.iter().collect())
+                .or_default()
+                .push(i + NGRAM_CHARS);
+        }
+
+        ReferencePrompts {
+            text,
+            offsets,
+            ngrams,
+            duration,
+            anchor: None,
+        }
+    }
+
+    /// Index of the normalized character estimated to be spoken at `seconds`.
+    fn estimate(&self, seconds: f64) -> usize {
+        let (time, index) = self.anchor.unwrap_or((0.0, 0));
+        let rate = match self.anchor {
+            Some((time, index)) if time > 0.0 && index > 0 => Some(index as f64 / time),
+            _ => self
+                .duration
+                .filter(|duration| *duration > 0.0)
+                .map(|duration| self.offsets.len() as f64 / duration),
+        };
+
+        let estimate = index as f64 + (seconds - time) * rate.unwrap_or(0.0);
+        (estimate.max(0.0) as usize).min(self.offsets.len())
+    }
+
+    /// The reference text starting at the line estimated to be spoken at `seconds`.
+    pub fn prompt(&self, seconds: f64) -> &str {
+        let offset = self
+            .offsets
+            .get(self.estimate(seconds))
+            .copied()
+            .unwrap_or(self.text.len());
+        let start = self.text[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let text = &self.text[start..];
+        let end = text
+            .char_indices()
+            .nth(MAX_PROMPT_CHARS)
+            .map_or(text.len(), |(i, _)| i);
+
+        text[..end].trim()
+    }
+
+    /// The reference text estimated to be spoken between `start` and `end` seconds, extended to
+    /// whole lines.
+    pub fn expected(&self, start: f64, end: f64) -> &str {
+        let offset = |index: usize| self.offsets.get(index).copied().unwrap_or(self.text.len());
+        let start = offset(self.estimate(start).saturating_sub(EXPECTED_MARGIN_CHARS));
+        let end = offset(self.estimate(end) + EXPECTED_MARGIN_CHARS);
+        let start = self.text[..start].rfind('\n').map_or(0, |i| i + 1);
+        let end = self.text[end..]
+            .find('\n')
+            .map_or(self.text.len(), |i| end + i);
+
+        &self.text[start..end]
+    }
+
+    /// Looks up where the `transcript` of a section ending at `seconds` ends in the reference, and
+    /// continues estimating from there if it was found.
+    pub fn update(&mut self, seconds: f64, transcript: &str) {
+        let chars = normalize(transcript)
+            .map(|(_, chr)| chr)
+            .collect::<Vec<_>>();
+        let tail = &chars[chars.len().saturating_sub(MATCHED_TAIL_CHARS)..];
+        let expected = self.estimate(seconds);
+
+        // Every n-gram of the tail votes for where the tail ends in the reference, nearby votes
+        // are counted together to tolerate whisper missing or adding a few characters.
+        let mut votes: HashMap<usize, usize> = HashMap::new();
+        for (i, window) in tail.windows(NGRAM_CHARS).enumerate() {
+            let remaining = tail.len() - i - NGRAM_CHARS;
+            let Some(positions) = self.ngrams.get(&window.iter().collect::<String>()) else {
+                continue;
+            };
+            if positions.len() > MAX_NGRAM_OCCURRENCES {
+                continue;
+            }
+            for position in positions {
+                *votes
+                    .entry((position + remaining) / NGRAM_CHARS)
+                    .or_default() += 1;
+            }
+        }
+
+        let best = votes
+            .into_iter()
+            .filter(|(_, count)| *count >= MIN_VOTES)
+            .max_by_key(|&(bucket, count)| {
+                (
+                    count,
+                    std::cmp::Reverse((bucket * NGRAM_CHARS).abs_diff(expected)),
+                )
+            });
+        match best {
+            Some((bucket, _)) => {
+                let index = (bucket * NGRAM_CHARS).min(self.offsets.len());
+                log::info!(
+                    "Section ending at {seconds:.2}s matched the reference at character {index}, expected {expected}"
+                );
+                self.anchor = Some((seconds, index));
+            }
+            None => log::info!(
+                "Could not find the section ending at {seconds:.2}s in the reference, estimating its position"
+            ),
+        }
+    }
+}
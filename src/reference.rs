@@ -0,0 +1,633 @@
+//! Reference texts which are subtitle or lyrics files, every cue is aligned as a single line and
+//! subtitle files can be written back with only the times of their cues replaced.
+
+use std::{io::Write, ops::Range, path::Path, str::FromStr};
+
+use crate::{
+    error::{Error, Result},
+    output::{format_ass_timestamp, format_timestamp, OutputFormat},
+    timing::TimedLine,
+};
+
+/// Format of a reference text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReferenceFormat {
+    /// Plain text, every line is aligned on its own.
+    Text,
+    Srt,
+    Vtt,
+    Ass,
+    /// LRC lyrics, with or without timestamps.
+    Lrc,
+}
+
+impl ReferenceFormat {
+    /// Anything that isn't a known subtitle extension is plain text.
+    pub fn from_extension(path: &Path) -> Self {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| extension.parse().ok())
+            .unwrap_or(Self::Text)
+    }
+
+    /// The output format which the reference can be written back as.
+    pub fn output_format(self) -> Option<OutputFormat> {
+        match self {
+            Self::Text => None,
+            Self::Srt => Some(OutputFormat::Srt),
+            Self::Vtt => Some(OutputFormat::Vtt),
+            Self::Ass => Some(OutputFormat::Ass),
+            Self::Lrc => Some(OutputFormat::Lrc),
+        }
+    }
+}
+
+impl FromStr for ReferenceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "txt" | "text" => Ok(Self::Text),
+            "srt" => Ok(Self::Srt),
+            "vtt" => Ok(Self::Vtt),
+            "ass" | "ssa" => Ok(Self::Ass),
+            "lrc" => Ok(Self::Lrc),
+            _ => Err(format!(
+                "unknown reference format {s:?}, expected one of text, srt, vtt, ass or lrc"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for ReferenceFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Text => "text",
+            Self::Srt => "SubRip",
+            Self::Vtt => "WebVTT",
+            Self::Ass => "ASS",
+            Self::Lrc => "LRC",
+        })
+    }
+}
+
+/// A reference text as read from a file.
+pub enum Reference {
+    Text(String),
+    Subtitles(Subtitles),
+    Lyrics(Lyrics),
+}
+
+impl Reference {
+    pub fn parse(source: String, format: ReferenceFormat) -> Result<Self> {
+        match format {
+            ReferenceFormat::Text => Ok(Self::Text(source)),
+            ReferenceFormat::Lrc => Ok(Self::Lyrics(Lyrics::parse(&source))),
+            format => Subtitles::parse(&source, format).map(Self::Subtitles),
+        }
+    }
+
+    /// The text to align, one line per cue for subtitle files.
+    pub fn text(&self) -> &str {
+        match self {
+            Self::Text(text) => text,
+            Self::Subtitles(subtitles) => &subtitles.text,
+            Self::Lyrics(lyrics) => &lyrics.text,
+        }
+    }
+}
+
+/// A piece of the subtitle file, the timestamps of cues are left out so they can be replaced.
+enum Chunk {
+    Text(String),
+    Start(usize),
+    End(usize),
+}
+
+struct Cue {
+    start: i64,
+    end: i64,
+    /// Line of the cue in the text to align, `None` if it contains nothing to align.
+    line: Option<usize>,
+}
+
+/// A subtitle file used as the reference.
+pub struct Subtitles {
+    format: ReferenceFormat,
+    chunks: Vec<Chunk>,
+    cues: Vec<Cue>,
+    text: String,
+    /// Number of lines of `text`.
+    lines: usize,
+}
+
+/// Parses a timestamp of any of the supported formats (`[H:]MM:SS.fff`, `HH:MM:SS,fff` or
+/// `H:MM:SS.ff`) into tens of milliseconds.
+fn parse_timestamp(s: &str) -> Option<i64> {
+    let (clock, fraction) = s.split_once(['.', ',']).unwrap_or((s, "0"));
+    if fraction.is_empty() || !fraction.chars().all(|chr| chr.is_ascii_digit()) {
+        return None;
+    }
+    let parts = clock
+        .split(':')
+        .map(|part| part.parse::<i64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if !(2..=3).contains(&parts.len()) {
+        return None;
+    }
+
+    let seconds = parts.into_iter().fold(0, |total, part| total * 60 + part);
+    let fraction: f64 = format!("0.{fraction}").parse().ok()?;
+    Some(seconds * 100 + (fraction * 100.).round() as i64)
+}
+
+/// Byte ranges of the start and end timestamps of a SubRip or WebVTT timing line.
+fn parse_timing_line(line: &str) -> Option<(Range<usize>, Range<usize>)> {
+    let arrow = line.find("-->")?;
+    let before = &line[..arrow];
+    let start = before.len() - before.trim_start().len()..before.trim_end().len();
+    let after = &line[arrow + 3..];
+    let end_start = arrow + 3 + after.len() - after.trim_start().len();
+    let end_end = line[end_start..]
+        .find(char::is_whitespace)
+        .map_or(line.len(), |i| end_start + i);
+    let end = end_start..end_end;
+
+    (parse_timestamp(&line[start.clone()]).is_some()
+        && parse_timestamp(&line[end.clone()]).is_some())
+    .then_some((start, end))
+}
+
+/// Removes everything between `open` and `close`, unterminated tags are kept.
+fn strip_tags(text: &str, open: char, close: char) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(open) {
+        let Some(end) = rest[start..].find(close) else {
+            break;
+        };
+        result += &rest[..start];
+        rest = &rest[start + end + close.len_utf8()..];
+    }
+    result + rest
+}
+
+/// Collapses the whitespace of the text of a cue, since every cue is aligned as a single line.
+fn join_lines<'a>(lines: impl IntoIterator<Item = &'a str>) -> String {
+    lines
+        .into_iter()
+        .flat_map(str::split_whitespace)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Text of a SubRip or WebVTT cue without its markup.
+fn cue_text(line: &str) -> String {
+    let text = strip_tags(&strip_tags(line, '<', '>'), '{', '}');
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+        .replace("&lrm;", "")
+        .replace("&rlm;", "")
+        .replace("&amp;", "&")
+}
+
+/// Text of an ASS dialogue line without its override tags, drawings and line breaks.
+fn ass_text(text: &str) -> String {
+    let mut result = String::new();
+    let mut drawing = false;
+    for (i, part) in text.split('{').enumerate() {
+        let plain = match part.split_once('}') {
+            Some((tags, plain)) if i != 0 => {
+                // `\p<scale>` starts drawing mode and `\p0` ends it, drawings are made of commands
+                // rather than text.
+                for tag in tags.split('\\') {
+                    if let Some(scale) = tag
+                        .strip_prefix('p')
+                        .and_then(|scale| scale.parse::<u32>().ok())
+                    {
+                        drawing = scale != 0;
+                    }
+                }
+                plain
+            }
+            _ => part,
+        };
+        if !drawing {
+            result += plain;
+        }
+    }
+
+    result
+        .replace("\\N", " ")
+        .replace("\\n", " ")
+        .replace("\\h", " ")
+}
+
+impl Subtitles {
+    fn parse(source: &str, format: ReferenceFormat) -> Result<Self> {
+        let mut subtitles = Subtitles {
+            format,
+            chunks: vec![],
+            cues: vec![],
+            text: String::new(),
+            lines: 0,
+        };
+        match format {
+            ReferenceFormat::Srt | ReferenceFormat::Vtt => subtitles.parse_cues(source),
+            ReferenceFormat::Ass => subtitles.parse_ass(source)?,
+            ReferenceFormat::Text | ReferenceFormat::Lrc => {
+                unreachable!("{format} is not a subtitle format")
+            }
+        }
+
+        if subtitles.cues.is_empty() {
+            return Err(Error::Invalid(format!(
+                "the {format} reference contains no cues"
+            )));
+        }
+        Ok(subtitles)
+    }
+
+    fn push_text(&mut self, text: &str) {
+        match self.chunks.last_mut() {
+            Some(Chunk::Text(last)) => *last += text,
+            _ => self.chunks.push(Chunk::Text(text.to_string())),
+        }
+    }
+
+    /// Adds `line` with the timestamps at `start` and `end` cut out, along with the cue they belong
+    /// to.
+    fn push_cue(&mut self, line: &str, start: Range<usize>, end: Range<usize>, text: String) {
+        let cue = self.cues.len();
+        let mut slots = [
+            (start.clone(), Chunk::Start(cue)),
+            (end.clone(), Chunk::End(cue)),
+        ];
+        slots.sort_by_key(|(range, _)| range.start);
+
+        let mut position = 0;
+        for (range, chunk) in slots {
+            self.push_text(&line[position..range.start]);
+            self.chunks.push(chunk);
+            position = range.end;
+        }
+        self.push_text(&line[position..]);
+
+        let aligned = (!text.is_empty()).then(|| {
+            self.text += &text;
+            self.text += "\n";
+            self.lines += 1;
+            self.lines - 1
+        });
+        self.cues.push(Cue {
+            start: parse_timestamp(&line[start]).unwrap(),
+            end: parse_timestamp(&line[end]).unwrap(),
+            line: aligned,
+        });
+    }
+
+    /// Parses SubRip and WebVTT files, which both consist of blocks of a timing line followed by
+    /// the lines of the cue. Everything else, including cue IDs and settings, is kept as is.
+    fn parse_cues(&mut self, source: &str) {
+        let mut lines = source.split_inclusive('\n').peekable();
+        while let Some(line) = lines.next() {
+            let Some((start, end)) = parse_timing_line(line.trim_end_matches(['\r', '\n'])) else {
+                self.push_text(line);
+                continue;
+            };
+
+            let mut text_lines = vec![];
+            while let Some(text_line) = lines.next_if(|line| !line.trim().is_empty()) {
+                text_lines.push(text_line);
+            }
+            let text = text_lines
+                .iter()
+                .map(|line| cue_text(line))
+                .collect::<Vec<_>>();
+            self.push_cue(
+                line,
+                start,
+                end,
+                join_lines(text.iter().map(String::as_str)),
+            );
+            for text_line in text_lines {
+                self.push_text(text_line);
+            }
+        }
+    }
+
+    /// Parses the `Dialogue` lines of the `[Events]` section of an ASS script, their fields are
+    /// found through the `Format` line of the section.
+    fn parse_ass(&mut self, source: &str) -> Result<()> {
+        let mut in_events = false;
+        let mut fields: Option<Vec<String>> = None;
+        for line in source.split_inclusive('\n') {
+            let content = line.trim_end_matches(['\r', '\n']);
+            let trimmed = content.trim();
+            if trimmed.starts_with('[') && trimmed.ends_with(']') {
+                in_events = trimmed.eq_ignore_ascii_case("[Events]");
+            }
+            if in_events {
+                if let Some(format) = trimmed.strip_prefix("Format:") {
+                    fields = Some(
+                        format
+                            .split(',')
+                            .map(|field| field.trim().to_ascii_lowercase())
+                            .collect(),
+                    );
+                }
+            }
+
+            let dialogue = content
+                .find("Dialogue:")
+                .filter(|&i| in_events && content[..i].trim().is_empty());
+            let (Some(dialogue), Some(fields)) = (dialogue, fields.as_ref()) else {
+                self.push_text(line);
+                continue;
+            };
+
+            let position = |name: &str| {
+                fields
+                    .iter()
+                    .position(|field| field == name)
+                    .ok_or_else(|| {
+                        Error::Invalid(format!(
+                            "the [Events] format of the ASS reference has no {name} field"
+                        ))
+                    })
+            };
+            let (start, end, text) = (position("start")?, position("end")?, position("text")?);
+
+            // The text is the last field and may contain commas itself.
+            let offset = dialogue + "Dialogue:".len();
+            let mut ranges = vec![];
+            let mut field_start = offset;
+            for (i, _) in content[offset..].match_indices(',').take(fields.len() - 1) {
+                ranges.push(field_start..offset + i);
+                field_start = offset + i + 1;
+            }
+            ranges.push(field_start..content.len());
+
+            let trim = |range: &Range<usize>| {
+                let field = &content[range.clone()];
+                let start = range.start + field.len() - field.trim_start().len();
+                start..start + field.trim().len()
+            };
+            let (Some(start), Some(end), Some(text)) =
+                (ranges.get(start), ranges.get(end), ranges.get(text))
+            else {
+                return Err(Error::Invalid(format!(
+                    "ASS dialogue line {trimmed:?} has fewer fields than its format"
+                )));
+            };
+            if parse_timestamp(&content[trim(start)]).is_none()
+                || parse_timestamp(&content[trim(end)]).is_none()
+            {
+                return Err(Error::Invalid(format!(
+                    "ASS dialogue line {trimmed:?} has invalid times"
+                )));
+            }
+            let text = join_lines([ass_text(&content[text.clone()]).as_str()]);
+            self.push_cue(line, trim(start), trim(end), text);
+        }
+
+        if fields.is_none() {
+            return Err(Error::Invalid(
+                "the ASS reference has no [Events] section with a Format line".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn format(&self) -> ReferenceFormat {
+        self.format
+    }
+
+    /// Writes the file back with the times of its cues taken from `lines`, which have to be timed
+    /// from the text of this reference. Cues without a timed line keep their original times, their
+    /// number is returned.
+    pub fn write_retimed<'a>(
+        &self,
+        lines: impl IntoIterator<Item = &'a TimedLine>,
+        mut output: impl Write,
+    ) -> std::io::Result<usize> {
+        let mut timed = vec![None; self.lines];
+        for line in lines {
+            if let Some(slot) = timed.get_mut(line.line) {
+                *slot = Some((line.start, line.end));
+            }
+        }
+
+        let times = self
+            .cues
+            .iter()
+            .map(|cue| {
+                cue.line
+                    .and_then(|line| timed[line])
+                    .unwrap_or((cue.start, cue.end))
+            })
+            .collect::<Vec<_>>();
+        let untimed = self
+            .cues
+            .iter()
+            .filter(|cue| cue.line.is_none_or(|line| timed[line].is_none()))
+            .count();
+        let format_time = |ts: i64| match self.format {
+            ReferenceFormat::Srt => format_timestamp(ts, ','),
+            ReferenceFormat::Ass => format_ass_timestamp(ts),
+            _ => format_timestamp(ts, '.'),
+        };
+        for chunk in self.chunks.iter() {
+            match *chunk {
+                Chunk::Text(ref text) => output.write_all(text.as_bytes())?,
+                Chunk::Start(cue) => output.write_all(format_time(times[cue].0).as_bytes())?,
+                Chunk::End(cue) => output.write_all(format_time(times[cue].1).as_bytes())?,
+            }
+        }
+
+        Ok(untimed)
+    }
+}
+
+/// Header tags of LRC files which are kept in the output.
+const LRC_KEPT_HEADERS: [&str; 3] = ["ar", "ti", "al"];
+
+/// Lyrics read from an LRC file, its timestamps are ignored except for ordering the lines.
+pub struct Lyrics {
+    text: String,
+    headers: Vec<String>,
+}
+
+impl Lyrics {
+    /// Lines with several timestamps are repeated once per timestamp. If every line is timed, the
+    /// lines are sorted by their timestamps, since that is the order in which they are sung.
+    fn parse(source: &str) -> Self {
+        let mut headers = vec![];
+        let mut lines: Vec<(Option<i64>, String)> = vec![];
+        for line in source.trim_start_matches('\u{feff}').lines() {
+            let mut rest = line.trim();
+            let mut times = vec![];
+            while let Some((tag, after)) =
+                rest.strip_prefix('[').and_then(|tag| tag.split_once(']'))
+            {
+                if let Some(time) = parse_timestamp(tag.trim()) {
+                    times.push(time);
+                } else if let Some((key, _)) = tag.split_once(':') {
+                    if LRC_KEPT_HEADERS.contains(&key.trim().to_ascii_lowercase().as_str()) {
+                        headers.push(format!("[{tag}]"));
+                    }
+                } else {
+                    break;
+                }
+                rest = after.trim_start();
+            }
+
+            // Word timestamps of enhanced LRC.
+            let text = join_lines([strip_tags(rest, '<', '>').as_str()]);
+            if text.is_empty() {
+                continue;
+            }
+            if times.is_empty() {
+                lines.push((None, text));
+            } else {
+                lines.extend(times.into_iter().map(|time| (Some(time), text.clone())));
+            }
+        }
+
+        if lines.iter().all(|(time, _)| time.is_some()) {
+            lines.sort_by_key(|(time, _)| *time);
+        }
+        Lyrics {
+            text: lines.into_iter().map(|(_, text)| text + "\n").collect(),
+            headers,
+        }
+    }
+
+    /// Header tags like `[ar:Artist]` in the order they appeared in the file.
+    pub fn headers(&self) -> &[String] {
+        &self.headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subtitles(source: &str, format: ReferenceFormat) -> Subtitles {
+        match Reference::parse(source.to_string(), format).unwrap() {
+            Reference::Subtitles(subtitles) => subtitles,
+            _ => unreachable!(),
+        }
+    }
+
+    fn timed(line: usize, start: i64, end: i64) -> TimedLine {
+        TimedLine {
+            start,
+            end,
+            text: String::new(),
+            words: vec![],
+            line,
+            speaker: None,
+        }
+    }
+
+    fn retimed(subtitles: &Subtitles, lines: &[TimedLine]) -> String {
+        let mut output = vec![];
+        subtitles.write_retimed(lines, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn srt_crlf_bom() {
+        let source = "\u{feff}1\r\n00:00:01,000 --> 00:00:02,000\r\n<i>Hello</i> world\r\n\r\n\
+                      2\r\n00:00:03,000 --> 00:00:04,500\r\nSecond cue\r\non two lines\r\n\r\n";
+        let subtitles = subtitles(source, ReferenceFormat::Srt);
+        assert_eq!(subtitles.text, "Hello world\nSecond cue on two lines\n");
+
+        assert_eq!(retimed(&subtitles, &[]), source);
+        assert_eq!(
+            retimed(&subtitles, &[timed(1, 310, 420)]),
+            "\u{feff}1\r\n00:00:01,000 --> 00:00:02,000\r\n<i>Hello</i> world\r\n\r\n\
+             2\r\n00:00:03,100 --> 00:00:04,200\r\nSecond cue\r\non two lines\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn vtt_ids_and_settings() {
+        let source =
+            "WEBVTT\n\nNOTE a comment\n\nintro\n00:01.000 --> 00:02.000 align:start position:10%\n\
+                      <v Alice>Hi &amp; welcome\n\n00:00:03.000 --> 00:00:04.000\nBye\n";
+        let subtitles = subtitles(source, ReferenceFormat::Vtt);
+        assert_eq!(subtitles.text, "Hi & welcome\nBye\n");
+
+        assert_eq!(
+            retimed(&subtitles, &[timed(0, 120, 190), timed(1, 300, 350)]),
+            "WEBVTT\n\nNOTE a comment\n\nintro\n00:00:01.200 --> 00:00:01.900 align:start position:10%\n\
+             <v Alice>Hi &amp; welcome\n\n00:00:03.000 --> 00:00:03.500\nBye\n"
+        );
+    }
+
+    #[test]
+    fn ass_text_with_commas() {
+        let source = "[Script Info]\nTitle: Test\n\n[Events]\n\
+                      Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n\
+                      Comment: 0,0:00:00.00,0:00:01.00,Default,,0,0,0,,Not aligned\n\
+                      Dialogue: 0,0:00:01.00,0:00:02.00,Default,,0,0,0,,{\\i1}Well,{\\i0} hello, world\\Nagain\n\
+                      Dialogue: 0,0:00:03.00,0:00:04.00,Default,,0,0,0,,Bye\n";
+        let subtitles = subtitles(source, ReferenceFormat::Ass);
+        assert_eq!(subtitles.text, "Well, hello, world again\nBye\n");
+
+        assert_eq!(
+            retimed(&subtitles, &[timed(0, 150, 250)]),
+            source.replace("0:00:01.00,0:00:02.00", "0:00:01.50,0:00:02.50")
+        );
+    }
+
+    #[test]
+    fn ass_format_fields() {
+        let source = "[Events]\nFormat: Marked, Start, End, Style, Text\n\
+                      Dialogue: Marked=0, 0:00:01.00, 0:00:02.00, Default, Hi, there\n";
+        let subtitles = subtitles(source, ReferenceFormat::Ass);
+        assert_eq!(subtitles.text, "Hi, there\n");
+        assert_eq!(
+            retimed(&subtitles, &[timed(0, 110, 190)]),
+            "[Events]\nFormat: Marked, Start, End, Style, Text\n\
+             Dialogue: Marked=0, 0:00:01.10, 0:00:01.90, Default, Hi, there\n"
+        );
+    }
+
+    fn lyrics(source: &str) -> Lyrics {
+        match Reference::parse(source.to_string(), ReferenceFormat::Lrc).unwrap() {
+            Reference::Lyrics(lyrics) => lyrics,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn lrc_repeated_lines() {
+        let lyrics = lyrics(
+            "[00:01.00]Verse one\n[00:10.00][00:30.50]Chorus\n[00:20.00]<00:20.00>Verse <00:21.00>two\n",
+        );
+        assert_eq!(lyrics.text, "Verse one\nChorus\nVerse two\nChorus\n");
+    }
+
+    #[test]
+    fn lrc_untimed_lines_keep_order() {
+        let lyrics = lyrics("[00:10.00]Second\nUntimed\n[00:01.00]First\n");
+        assert_eq!(lyrics.text, "Second\nUntimed\nFirst\n");
+    }
+
+    #[test]
+    fn lrc_headers() {
+        let lyrics = lyrics(
+            "\u{feff}[ar:Artist]\r\n[ti: Title]\r\n[by:Someone]\r\n[offset:+100]\r\n[al:Album]\r\n\
+             [00:01.00]Hello\r\n",
+        );
+        assert_eq!(
+            lyrics.headers(),
+            ["[ar:Artist]", "[ti: Title]", "[al:Album]"]
+        );
+        assert_eq!(lyrics.text, "Hello\n");
+    }
+}
@@ -0,0 +1,210 @@
+//! Splitting of a stream of 16kHz audio samples into sections which are transcribed separately.
+
+use std::ops::Range;
+
+use crate::{
+    error::{Context, Error, Result},
+    silero,
+    whisper::SileroOptions,
+};
+
+/// Number of samples silero looks at at once.
+pub const CHUNK_SAMPLES: usize = 480;
+
+/// The samples of an audio stream which may still be needed, older ones are discarded.
+#[derive(Default)]
+pub struct SampleBuffer {
+    offset: usize,
+    samples: Vec<f32>,
+}
+
+impl SampleBuffer {
+    pub fn extend_from_slice(&mut self, samples: &[f32]) {
+        self.samples.extend_from_slice(samples);
+    }
+
+    /// Total number of samples that were ever added to the buffer.
+    pub fn end(&self) -> usize {
+        self.offset + self.samples.len()
+    }
+
+    pub fn slice(&self, range: Range<usize>) -> &[f32] {
+        &self.samples[range.start - self.offset..range.end - self.offset]
+    }
+
+    pub fn discard_before(&mut self, position: usize) {
+        let count = position.saturating_sub(self.offset).min(self.samples.len());
+        // Only move samples around once a sizeable part of the buffer can be freed.
+        if count >= self.samples.len() / 2 {
+            self.samples.drain(..count);
+            self.offset += count;
+        }
+    }
+}
+
+struct Vad {
+    silero: silero::Silero,
+    threshold: f32,
+    min_silence_chunks: usize,
+    min_trim_silence_chunks: usize,
+    padding_samples: usize,
+    trim_padding_samples: usize,
+    silence_chain_length: usize,
+    is_at_start: bool,
+}
+
+/// Decides where sections of speech start and end while the audio is being decoded.
+///
+/// Without a VAD the whole audio is a single section, in both cases sections are cut after
+/// `max_section_seconds` so that only a bounded amount of audio has to be kept in memory.
+pub struct SectionSplitter {
+    vad: Option<Vad>,
+    current_start: usize,
+    max_section_samples: usize,
+}
+
+impl SectionSplitter {
+    pub fn new(silero: Option<SileroOptions>, max_section_seconds: f32) -> Result<Self> {
+        let vad = if let Some(SileroOptions {
+            path,
+            threshold,
+            min_silence_seconds,
+            min_trim_silence_seconds,
+            speech_padding_seconds,
+        }) = silero
+        {
+            if min_silence_seconds <= 0.5 {
+                return Err(Error::Invalid(
+                    "VAD minimum silence duration has to be longer than 0.5s".to_string(),
+                ));
+            }
+            let min_silence_chunks = (min_silence_seconds * (16000. / 480.)) as usize;
+            let min_trim_silence_chunks = (min_trim_silence_seconds * (16000. / 480.)) as usize;
+            let padding_samples = (speech_padding_seconds * 16000.) as usize;
+            if padding_samples / 480 > std::cmp::min(min_silence_chunks, min_trim_silence_chunks) {
+                return Err(Error::Invalid(
+                    "VAD speech padding has to be shorter than the minimum silence duration"
+                        .to_string(),
+                ));
+            }
+
+            Some(Vad {
+                silero: silero::Silero::new(silero::SampleRate::Hz16000, &path)
+                    .with_context(|| format!("failed to load silero model {}", path.display()))?,
+                threshold,
+                min_silence_chunks,
+                min_trim_silence_chunks,
+                padding_samples,
+                trim_padding_samples: padding_samples,
+                silence_chain_length: 0,
+                is_at_start: true,
+            })
+        } else {
+            None
+        };
+
+        Ok(SectionSplitter {
+            vad,
+            current_start: 0,
+            max_section_samples: (max_section_seconds * 16000.) as usize,
+        })
+    }
+
+    /// Feeds the chunk of [`CHUNK_SAMPLES`] samples starting at `position`, only the last chunk of
+    /// the audio may be shorter.
+    ///
+    /// Returns the section that ended with this chunk, if any.
+    pub fn push(&mut self, position: usize, chunk: &[f32]) -> Result<Option<Range<usize>>> {
+        let chunk_end = position + chunk.len();
+        let mut section = None;
+
+        if let Some(vad) = self.vad.as_mut() {
+            let i = position / CHUNK_SAMPLES;
+            let mut padded;
+            let chunk = if chunk.len() < CHUNK_SAMPLES {
+                padded = Vec::with_capacity(CHUNK_SAMPLES);
+                padded.extend_from_slice(chunk);
+                padded.resize(CHUNK_SAMPLES, 0.0);
+                padded.as_slice()
+            } else {
+                chunk
+            };
+            let speech_probability = vad
+                .silero
+                .run(chunk)
+                .context("failed to run silero on audio")?;
+
+            log::info!(
+                "silero: {i} chunk {:.2}s = {:.2}",
+                position as f64 / 16000.0,
+                speech_probability
+            );
+
+            if speech_probability <= vad.threshold {
+                vad.silence_chain_length += 1;
+                if vad.is_at_start {
+                    self.current_start = position;
+                }
+            } else {
+                if vad.is_at_start {
+                    self.current_start =
+                        self.current_start.saturating_sub(vad.trim_padding_samples);
+                    vad.is_at_start = false;
+                } else if vad.silence_chain_length >= vad.min_silence_chunks {
+                    section = Some(
+                        self.current_start
+                            ..(i - vad.silence_chain_length) * CHUNK_SAMPLES + vad.padding_samples,
+                    );
+                    self.current_start = position - vad.padding_samples;
+                }
+                vad.silence_chain_length = 0;
+            }
+
+            if vad.is_at_start {
+                return Ok(None);
+            }
+        }
+
+        if section.is_none() && chunk_end - self.current_start >= self.max_section_samples {
+            log::info!(
+                "Cutting section at {:.2}s, it is longer than {:.2}s",
+                chunk_end as f64 / 16000.,
+                self.max_section_samples as f64 / 16000.
+            );
+            section = Some(self.current_start..chunk_end);
+            self.current_start = chunk_end;
+            if let Some(vad) = self.vad.as_mut() {
+                vad.silence_chain_length = 0;
+            }
+        }
+
+        Ok(section)
+    }
+
+    /// Samples before the returned position will not be part of any section anymore.
+    pub fn needed_from(&self) -> usize {
+        match self.vad.as_ref() {
+            Some(vad) if vad.is_at_start => {
+                self.current_start.saturating_sub(vad.trim_padding_samples)
+            }
+            _ => self.current_start,
+        }
+    }
+
+    /// Returns the last section once all `end` samples of the audio were pushed.
+    pub fn finish(self, end: usize) -> Option<Range<usize>> {
+        let section_end = if let Some(mut vad) = self.vad {
+            if vad.silence_chain_length < vad.min_trim_silence_chunks {
+                vad.silence_chain_length = 0;
+            }
+
+            end.saturating_sub(vad.silence_chain_length * CHUNK_SAMPLES)
+                .saturating_add(vad.trim_padding_samples)
+                .clamp(0, end)
+        } else {
+            end
+        };
+
+        (self.current_start < section_end).then_some(self.current_start..section_end)
+    }
+}
@@ -0,0 +1,614 @@
+//! Timing of reference lines based on their alignment with a whisper transcription.
+//!
+//! All times are in tens of milliseconds, just like in a [`Transcription`].
+
+use serde::Serialize;
+
+use crate::{
+    align,
+    tokenize::Tokenizer,
+    transcription::{Token, Transcription},
+};
+
+/// A piece of text together with its start and end.
+#[derive(Clone, Debug, Serialize)]
+pub struct TimedToken {
+    pub text: String,
+    pub start: i64,
+    pub end: i64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ReferenceToken<'a> {
+    text: &'a str,
+    start: i64,
+    end: i64,
+}
+
+/// Assigns a start and end to every reference token in `alignment`.
+///
+/// Tokens matched with a whisper token take its times, runs of unmatched tokens are spread over the
+/// time between their matched neighbours proportionally to their length in characters.
+fn time_reference_tokens<'a>(
+    alignment: &[(Option<&TimedToken>, Option<&'a str>, f64)],
+) -> Vec<ReferenceToken<'a>> {
+    let first_start = alignment
+        .iter()
+        .find_map(|x| x.0.map(|t| t.start))
+        .unwrap_or(0);
+    let last_end = alignment
+        .iter()
+        .rev()
+        .find_map(|x| x.0.map(|t| t.end))
+        .unwrap_or(first_start);
+
+    let mut result = vec![];
+    let mut pending = vec![];
+    let interpolate = |pending: &mut Vec<&'a str>, result: &mut Vec<_>, from: i64, to: i64| {
+        let to = to.max(from);
+        let weight = |text: &str| text.trim().chars().count() as i64;
+        let total = pending.iter().copied().map(weight).sum::<i64>().max(1);
+        let mut done = 0;
+        for text in pending.drain(..) {
+            let start = from + (to - from) * done / total;
+            done += weight(text);
+            result.push(ReferenceToken {
+                text,
+                start,
+                end: from + (to - from) * done / total,
+            });
+        }
+    };
+
+    let mut previous_end = first_start;
+    for (a, b, _) in alignment.iter().copied() {
+        match (a, b) {
+            // Whitespace is not a good anchor since its timing is mostly made up.
+            (Some(a), Some(b)) if !b.trim().is_empty() => {
+                interpolate(&mut pending, &mut result, previous_end, a.start);
+                result.push(ReferenceToken {
+                    text: b,
+                    start: a.start,
+                    end: a.end,
+                });
+                previous_end = a.end;
+            }
+            (_, Some(b)) => pending.push(b),
+            _ => (),
+        }
+    }
+    interpolate(&mut pending, &mut result, previous_end, last_end);
+
+    result
+}
+
+impl From<ReferenceToken<'_>> for TimedToken {
+    fn from(value: ReferenceToken<'_>) -> Self {
+        Self {
+            text: value.text.to_string(),
+            start: value.start,
+            end: value.end,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TimedWord {
+    pub start: i64,
+    pub end: i64,
+    /// Byte offset of this word in the text of its line.
+    pub offset: usize,
+    pub text: String,
+}
+
+/// A single line of the reference text with its timing.
+#[derive(Debug, Serialize)]
+pub struct TimedLine {
+    pub start: i64,
+    pub end: i64,
+    pub text: String,
+    pub words: Vec<TimedWord>,
+    /// Index of the line in the reference text, counting every line break.
+    #[serde(skip)]
+    pub line: usize,
+    /// Set if the speaker label of the line was moved out of its text, see
+    /// [`Markup::restore`](crate::markup::Markup::restore).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speaker: Option<String>,
+}
+
+impl TimedLine {
+    /// Splits this line into one line per word.
+    pub fn into_word_lines(self) -> impl Iterator<Item = TimedLine> {
+        let line = self.line;
+        let speaker = self.speaker;
+        self.words.into_iter().map(move |word| TimedLine {
+            start: word.start,
+            end: word.end,
+            text: word.text.clone(),
+            words: vec![TimedWord { offset: 0, ..word }],
+            line,
+            speaker: speaker.clone(),
+        })
+    }
+}
+
+/// Where the start or end of a line was taken from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeSource {
+    /// Taken from the whisper tokens of the line itself.
+    Direct,
+    /// Taken from a neighbouring line or the start/end of the transcription.
+    Borrowed,
+    /// Could not be determined, the line was left out of the output.
+    Skipped,
+    /// The line is outside the part of the reference matching a partial transcription, it was left
+    /// out of the output.
+    Outside,
+}
+
+/// A single step of the token alignment.
+#[derive(Clone, Debug, Serialize)]
+pub struct PairReport {
+    pub whisper: Option<TimedToken>,
+    /// The reference token along with its timing after interpolation.
+    pub reference: Option<TimedToken>,
+    pub cost: f64,
+}
+
+/// Describes how a single line of the reference was timed.
+#[derive(Clone, Debug, Serialize)]
+pub struct LineReport {
+    pub text: String,
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+    pub start_source: TimeSource,
+    pub end_source: TimeSource,
+    pub pairs: Vec<PairReport>,
+}
+
+/// Result of [`align_transcription`].
+#[derive(Debug)]
+pub struct Alignment {
+    /// All lines that could be timed.
+    pub lines: Vec<TimedLine>,
+    /// A report for every line of the reference, including the ones that couldn't be timed.
+    pub report: Vec<LineReport>,
+    pub strategy: align::AlignStrategy,
+}
+
+/// Options for [`align_transcription`].
+#[derive(Clone, Debug, Default)]
+pub struct AlignOptions {
+    /// Use the DTW timestamps of whisper tokens instead of their `start` and `end` where available.
+    pub prefer_dtw: bool,
+    /// Only align tokens between unique words found on both sides, see [`align::align_anchored`].
+    ///
+    /// Much faster for long transcriptions but may give worse results if whisper's output differs a
+    /// lot from the reference.
+    pub anchored: bool,
+    /// Leave out the reference lines before the first and after the last line containing a word
+    /// whisper transcribed the same way, for transcriptions covering only a part of the audio.
+    pub restrict_reference: bool,
+}
+
+/// Whether a whisper token and a reference token are the same word.
+fn is_exact_match(whisper: &TimedToken, reference: &str) -> bool {
+    let reference = reference.trim();
+    !reference.is_empty() && whisper.text.trim().to_lowercase() == reference.to_lowercase()
+}
+
+/// Start and end of the token at `index` in `segment`, taking DTW timestamps into account if asked to.
+///
+/// DTW only gives a single point in time per token, so a token is considered to last until the
+/// next one starts.
+fn token_times(segment: &[Token], index: usize, options: &AlignOptions) -> (i64, i64) {
+    let token = &segment[index];
+    match token.dtw {
+        Some(start) if options.prefer_dtw => {
+            let end = segment
+                .get(index + 1)
+                .and_then(|next| next.dtw)
+                .unwrap_or(token.end)
+                .max(start);
+            (start, end)
+        }
+        _ => (token.start, token.end),
+    }
+}
+
+/// Aligns `reference` with `transcription` and times every line of the reference text.
+pub fn align_transcription(
+    transcription: &Transcription,
+    reference: &str,
+    tokenizer: &mut dyn Tokenizer,
+    options: &AlignOptions,
+) -> Alignment {
+    let mut byte_starts = vec![];
+    let mut byte_ends = vec![];
+    let mut whisper_sentence = String::new();
+    // Segments without text would only add a line break which has no time to take.
+    let segments = transcription
+        .segments
+        .iter()
+        .filter(|segment| segment.iter().any(|token| !token.text.is_empty()));
+    for segment in segments {
+        for (i, token) in segment.iter().enumerate() {
+            let (start, end) = token_times(segment, i, options);
+            let bytes = token.text.len();
+            let byte_duration = (end - start) / bytes.max(1) as i64;
+            let mut current = start;
+            for _ in 0..bytes {
+                byte_starts.push(current);
+                current += byte_duration;
+                byte_ends.push(current);
+            }
+            whisper_sentence += &token.text
+        }
+
+        byte_starts.push(*byte_ends.last().unwrap());
+        byte_ends.push(*byte_ends.last().unwrap());
+        whisper_sentence += "\n";
+    }
+    assert_eq!(whisper_sentence.len(), byte_ends.len());
+    assert_eq!(whisper_sentence.len(), byte_starts.len());
+
+    log::info!("Tokenizing whisper sentence");
+    let whisper_tokens = tokenizer
+        .tokenize(&whisper_sentence)
+        .map(|range| TimedToken {
+            text: whisper_sentence[range.clone()].to_string(),
+            start: byte_starts[range.start],
+            end: byte_ends[range.end - 1],
+        })
+        .collect::<Vec<_>>();
+
+    log::info!("Tokenizing reference sentence");
+    let reference_ranges = tokenizer.tokenize(reference).collect::<Vec<_>>();
+    let reference_tokens = reference_ranges
+        .iter()
+        .map(|range| &reference[range.clone()])
+        .collect::<Vec<_>>();
+    // Line of the first visible character of every reference token, or of its start if it's only
+    // whitespace. Counted from the byte offsets since the tokenizer may skip line breaks, e.g. those
+    // of leading empty lines.
+    let mut newlines = 0;
+    let mut position = 0;
+    let token_lines = reference_ranges
+        .iter()
+        .map(|range| {
+            let text = &reference[range.clone()];
+            let leading = text.len() - text.trim_start().len();
+            let visible = range.start + if leading == text.len() { 0 } else { leading };
+            newlines += reference[position..visible].matches('\n').count();
+            position = visible;
+            newlines
+        })
+        .collect::<Vec<_>>();
+
+    log::info!("Aligning tokens");
+    let (alignment, strategy) = align::text_align(
+        whisper_tokens.iter().map(|x| x.text.clone()),
+        reference_tokens.iter().copied().map(str::to_string),
+        options.anchored,
+    );
+    let pair_lines = alignment
+        .iter()
+        .map(|(_, b, _)| b.map(|i| token_lines[i]))
+        .collect::<Vec<_>>();
+    let alignment = alignment
+        .into_iter()
+        .map(|(a, b, cost)| {
+            (
+                a.map(|i| &whisper_tokens[i]),
+                b.map(|i| reference_tokens[i]),
+                cost,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mut timed_reference_tokens = time_reference_tokens(&alignment).into_iter();
+    let alignment = alignment
+        .into_iter()
+        .map(|(a, b, cost)| (a, b.map(|_| timed_reference_tokens.next().unwrap()), cost))
+        .collect::<Vec<_>>();
+
+    let mut reference_lines = vec![vec![]];
+    // Line of the reference text of the first visible token of every entry of `reference_lines`,
+    // and of its first token in case there are only whitespace tokens.
+    let mut line_numbers = vec![(None, None)];
+    for ((a, b, cost), line) in alignment.into_iter().zip(pair_lines) {
+        let is_line_boundary = b.as_ref().is_some_and(|x| x.text.contains("\n"));
+        if let (Some(b), Some(line)) = (b.as_ref(), line) {
+            let (visible, first) = line_numbers.last_mut().unwrap();
+            first.get_or_insert(line);
+            if !b.text.trim().is_empty() {
+                visible.get_or_insert(line);
+            }
+        }
+        reference_lines.last_mut().unwrap().push((a, b, cost));
+        if is_line_boundary {
+            reference_lines.push(vec![]);
+            line_numbers.push((None, None));
+        }
+    }
+    if reference_lines.last().unwrap().is_empty() {
+        reference_lines.pop();
+    }
+
+    // Lines which are timed, the others are left out of the output.
+    let mut kept = 0..reference_lines.len();
+    if options.restrict_reference {
+        let is_matched = |line: &Vec<(Option<&TimedToken>, Option<ReferenceToken>, f64)>| {
+            line.iter().any(|(a, b, _)| match (a, b) {
+                (Some(a), Some(b)) => is_exact_match(a, b.text),
+                _ => false,
+            })
+        };
+        match (
+            reference_lines.iter().position(is_matched),
+            reference_lines.iter().rposition(is_matched),
+        ) {
+            (Some(first), Some(last)) => kept = first..last + 1,
+            _ => log::info!(
+                "No reference line matches the transcription, not restricting the reference"
+            ),
+        }
+    }
+    // Where the transcribed audio starts, used for the first line if nothing else is known.
+    let audio_start = transcription
+        .range
+        .map_or(0, |range| (range.start * 100.) as i64);
+    let pair_reports = |line: &[(Option<&TimedToken>, Option<ReferenceToken>, f64)]| {
+        line.iter()
+            .map(|&(whisper, reference, cost)| PairReport {
+                whisper: whisper.cloned(),
+                reference: reference.map(TimedToken::from),
+                cost,
+            })
+            .collect()
+    };
+
+    let mut timed_lines: Vec<TimedLine> = vec![];
+    let mut report = vec![];
+
+    for i in 0..reference_lines.len() {
+        let current = &reference_lines[i];
+        let line = line_numbers[i].0.or(line_numbers[i].1).unwrap_or(0);
+
+        if current.iter().all(|x| x.1.is_none()) {
+            continue;
+        }
+
+        if !kept.contains(&i) {
+            let text = current
+                .iter()
+                .filter_map(|x| x.1.map(|token| token.text))
+                .collect::<String>()
+                .trim()
+                .to_string();
+            report.push(LineReport {
+                text,
+                start: None,
+                end: None,
+                start_source: TimeSource::Outside,
+                end_source: TimeSource::Outside,
+                pairs: pair_reports(current),
+            });
+            continue;
+        }
+
+        let mut start = None;
+        let mut it = current.iter().peekable();
+        while let Some((Some(x), None, _)) = it.peek() {
+            start = Some(x.end);
+            it.next();
+        }
+        if let Some((Some(x), Some(_), _)) = it.next() {
+            start = Some(x.start);
+        }
+
+        let (mut start, mut start_source) = if let Some(wt) = start {
+            (Some(wt), TimeSource::Direct)
+        } else if i > kept.start {
+            (
+                reference_lines[i - 1]
+                    .iter()
+                    .rev()
+                    .find_map(|x| x.0.as_ref().map(|x| x.end)),
+                TimeSource::Borrowed,
+            )
+        } else if i == kept.start {
+            (Some(audio_start), TimeSource::Borrowed)
+        } else {
+            (None, TimeSource::Skipped)
+        };
+        if start.is_none() {
+            start_source = TimeSource::Skipped;
+        }
+
+        if let (Some(start), Some(last)) = (&mut start, timed_lines.last()) {
+            if *start < last.end {
+                *start = last.end
+            }
+        }
+
+        let mut end = None;
+        let mut it = current.iter().rev().peekable();
+        while let Some((Some(x), None)) = it.peek().map(|x| {
+            (
+                x.0.as_ref(),
+                x.1.as_ref()
+                    .filter(|x| x.text.chars().all(|c| c.is_alphanumeric())),
+            )
+        }) {
+            end = Some(x.start);
+            it.next();
+        }
+        while let Some((None, Some(_))) = it.peek().map(|x| {
+            (
+                x.0.as_ref(),
+                x.1.as_ref()
+                    .filter(|x| x.text.chars().all(|c| !c.is_alphanumeric())),
+            )
+        }) {
+            it.next();
+        }
+        if let Some((Some(x), Some(_), _)) = it.next() {
+            end = Some(x.end);
+        }
+
+        let (end, mut end_source) = if let Some(wt) = end {
+            (Some(wt), TimeSource::Direct)
+        } else if let Some(end) = reference_lines
+            .get(i + 1)
+            .and_then(|v| v.iter().find_map(|x| x.0.as_ref().map(|t| t.start)))
+        {
+            (Some(end), TimeSource::Borrowed)
+        } else if i == kept.end - 1 {
+            (byte_ends.last().copied(), TimeSource::Borrowed)
+        } else {
+            (
+                current
+                    .iter()
+                    .rev()
+                    .find_map(|x| x.0.as_ref().map(|x| x.end)),
+                TimeSource::Direct,
+            )
+        };
+        if end.is_none() {
+            end_source = TimeSource::Skipped;
+        }
+
+        let mut line_text = String::new();
+        let mut words = vec![];
+        for token in current.iter().filter_map(|x| x.1) {
+            if !token.text.trim().is_empty() {
+                let leading_whitespace = token.text.len() - token.text.trim_start().len();
+                words.push(TimedWord {
+                    start: token.start,
+                    end: token.end,
+                    offset: line_text.len() + leading_whitespace,
+                    text: token.text.trim().to_string(),
+                });
+            }
+            line_text += token.text;
+        }
+        let trimmed_start = line_text.len() - line_text.trim_start().len();
+        let line_text = line_text.trim().to_string();
+        if let (Some(start), Some(end)) = (start, end) {
+            // Whisper's tokens may overlap, so the last one can end before the first one starts.
+            let end = end.max(start);
+            for word in words.iter_mut() {
+                word.offset -= trimmed_start;
+                word.start = word.start.clamp(start, end);
+                word.end = word.end.clamp(word.start, end);
+            }
+            timed_lines.push(TimedLine {
+                start,
+                end,
+                text: line_text.to_string(),
+                words,
+                line,
+                speaker: None,
+            });
+        }
+
+        report.push(LineReport {
+            text: line_text,
+            start,
+            end,
+            start_source,
+            end_source,
+            pairs: pair_reports(current),
+        });
+    }
+
+    Alignment {
+        lines: timed_lines,
+        report,
+        strategy,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{transcription::TRANSCRIPTION_VERSION, WhitespaceTokenizer};
+
+    #[test]
+    fn overlapping_tokens() {
+        let tokens = [(" Alpha", 100, 500), (" Beta", 300, 400)]
+            .into_iter()
+            .map(|(text, start, end)| Token {
+                probability: 1.0,
+                start,
+                end,
+                text: text.to_string(),
+                dtw: None,
+            })
+            .collect();
+        let transcription = Transcription {
+            version: TRANSCRIPTION_VERSION,
+            language: "en".to_string(),
+            language_probability: None,
+            metadata: None,
+            range: None,
+            segments: vec![tokens],
+        };
+
+        let alignment = align_transcription(
+            &transcription,
+            "Alpha\nBeta",
+            &mut WhitespaceTokenizer,
+            &AlignOptions::default(),
+        );
+        assert_eq!(alignment.lines.len(), 2);
+        for line in alignment.lines.iter() {
+            assert!(line.start <= line.end, "{line:?}");
+            for word in line.words.iter() {
+                assert!(line.start <= word.start && word.start <= word.end && word.end <= line.end);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_segment_and_token() {
+        let token = |text: &str, start, end| Token {
+            probability: 1.0,
+            start,
+            end,
+            text: text.to_string(),
+            dtw: None,
+        };
+        let transcription = Transcription {
+            version: TRANSCRIPTION_VERSION,
+            language: "en".to_string(),
+            language_probability: None,
+            metadata: None,
+            range: None,
+            segments: vec![
+                vec![],
+                vec![token("", 50, 100)],
+                vec![
+                    token(" Hello", 100, 150),
+                    token("", 150, 150),
+                    token(" world", 150, 200),
+                ],
+            ],
+        };
+
+        let alignment = align_transcription(
+            &transcription,
+            "Hello world",
+            &mut WhitespaceTokenizer,
+            &AlignOptions::default(),
+        );
+        let [line] = &alignment.lines[..] else {
+            panic!("expected a single line, got {:?}", alignment.lines);
+        };
+        assert_eq!(line.text, "Hello world");
+        assert!(100 <= line.start && line.start <= line.end && line.end <= 200);
+    }
+}
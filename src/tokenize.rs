@@ -0,0 +1,93 @@
+#[cfg(feature = "vibrato")]
+use std::mem::ManuallyDrop;
+use std::ops::Range;
+
+#[cfg(feature = "vibrato")]
+use vibrato::{tokenizer::worker::Worker, Dictionary};
+
+/// Splits text into tokens for alignment.
+pub trait Tokenizer {
+    /// Returns the byte ranges of the tokens of `text` in order.
+    ///
+    /// Whitespace may either be returned as separate tokens or skipped entirely, line breaks must
+    /// end up in some token though since they're used to split the reference into lines.
+    fn tokenize<'a>(&'a mut self, text: &'a str) -> Box<dyn Iterator<Item = Range<usize>> + 'a>;
+}
+
+/// Tokenizer for Japanese text backed by a [vibrato] dictionary.
+#[cfg(feature = "vibrato")]
+pub struct VibratoTokenizer {
+    tokenizer: *mut vibrato::Tokenizer,
+    worker: ManuallyDrop<Worker<'static>>,
+}
+
+#[cfg(feature = "vibrato")]
+impl VibratoTokenizer {
+    pub fn new(dictionary: Dictionary) -> Self {
+        let tokenizer: *mut vibrato::Tokenizer =
+            Box::leak(Box::new(vibrato::Tokenizer::new(dictionary))) as *mut _;
+        let worker = unsafe { (*tokenizer).new_worker() };
+        Self {
+            tokenizer,
+            worker: ManuallyDrop::new(worker),
+        }
+    }
+}
+
+#[cfg(feature = "vibrato")]
+impl Drop for VibratoTokenizer {
+    fn drop(&mut self) {
+        unsafe {
+            ManuallyDrop::drop(&mut self.worker);
+            let _ = Box::from_raw(self.tokenizer);
+        }
+    }
+}
+
+#[cfg(feature = "vibrato")]
+impl Tokenizer for VibratoTokenizer {
+    fn tokenize<'a>(&'a mut self, text: &'a str) -> Box<dyn Iterator<Item = Range<usize>> + 'a> {
+        self.worker.reset_sentence(text);
+        self.worker.tokenize();
+        Box::new(self.worker.token_iter().map(|x| x.range_byte()))
+    }
+}
+
+/// Tokenizer that splits text on whitespace, the whitespace between words is returned as separate
+/// tokens.
+pub struct WhitespaceTokenizer;
+
+impl Tokenizer for WhitespaceTokenizer {
+    fn tokenize<'a>(&'a mut self, text: &'a str) -> Box<dyn Iterator<Item = Range<usize>> + 'a> {
+        let mut current = text
+            .find(|c: char| !c.is_whitespace())
+            .unwrap_or(text.len());
+        let mut next_whitespace = None;
+
+        Box::new(std::iter::from_fn(move || {
+            if let Some(ws) = next_whitespace.take() {
+                return Some(ws);
+            }
+
+            if current == text.len() {
+                return None;
+            }
+
+            let next = text[current..]
+                .find(char::is_whitespace)
+                .map(|x| x + current)
+                .unwrap_or(text.len());
+
+            let old_current = current;
+            current = text[next..]
+                .find(|c: char| !c.is_whitespace())
+                .map(|x| x + next)
+                .unwrap_or(text.len());
+            if next != text.len() {
+                next_whitespace = Some(next..current);
+            }
+
+            Some(old_current..next)
+        }))
+    }
+}
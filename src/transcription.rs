@@ -0,0 +1,357 @@
+//! The transcription format produced by `transcribe` and consumed by `align`.
+
+use std::{io::Read, path::PathBuf, str::FromStr};
+
+use serde::{de::Error as _, Deserialize, Serialize};
+
+/// Version of the transcription format written by this version of the crate.
+///
+/// Files written before the format was versioned don't have a `version` field and are version 1.
+pub const TRANSCRIPTION_VERSION: u64 = 2;
+
+/// A single whisper token, `start` and `end` are in tens of milliseconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Token {
+    pub probability: f32,
+    pub start: i64,
+    pub end: i64,
+    pub text: String,
+    /// Time at which whisper's DTW timestamps place the token, only present if they were enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dtw: Option<i64>,
+}
+
+/// Output of whisper for a whole audio file, split into the segments produced by whisper.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transcription {
+    pub version: u64,
+    pub language: String,
+    /// Probability whisper gave the language, only present if it was detected automatically.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language_probability: Option<f32>,
+    /// Only missing in files migrated from version 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Metadata>,
+    /// Only present if just a part of the audio was transcribed, timestamps are still relative to
+    /// the start of the whole audio.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<TimeRange>,
+    pub segments: Vec<Vec<Token>>,
+}
+
+/// A part of the audio in seconds, `end` is `None` if it extends to the end of the audio.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TimeRange {
+    pub start: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<f64>,
+}
+
+impl TimeRange {
+    /// Whether `seconds` lies within the range.
+    pub fn contains(&self, seconds: f64) -> bool {
+        seconds >= self.start && self.end.is_none_or(|end| seconds < end)
+    }
+}
+
+impl std::fmt::Display for TimeRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.2}s-", self.start)?;
+        if let Some(end) = self.end {
+            write!(f, "{end:.2}s")?;
+        }
+        Ok(())
+    }
+}
+
+impl Transcription {
+    /// Parses a transcription of any supported version, migrating it to the current format.
+    pub fn from_reader(reader: impl Read) -> serde_json::Result<Self> {
+        let mut value: serde_json::Value = serde_json::from_reader(reader)?;
+        let object = value
+            .as_object_mut()
+            .ok_or_else(|| serde_json::Error::custom("expected a transcription object"))?;
+        let version = match object.get("version") {
+            None => 1,
+            Some(version) => version.as_u64().ok_or_else(|| {
+                serde_json::Error::custom("transcription version is not a positive integer")
+            })?,
+        };
+
+        if version == 0 || version > TRANSCRIPTION_VERSION {
+            return Err(serde_json::Error::custom(format!(
+                "unsupported transcription version {version}, the newest supported one is {TRANSCRIPTION_VERSION}"
+            )));
+        }
+
+        if version == 1 {
+            // Version 1 only lacked the version and metadata.
+            object.insert("version".to_string(), 2.into());
+        }
+
+        serde_json::from_value(value)
+    }
+
+    /// Replaces the segments starting within the range of `other` with the segments of `other`.
+    ///
+    /// Segments starting before the range but extending into it lose their tokens ending after the
+    /// start of the range, since `other` transcribes that part again. The range of the result is the
+    /// smallest one covering both transcriptions, the language and metadata are kept from `self`.
+    pub fn merge(&mut self, other: Transcription) {
+        let range = other.range.unwrap_or(TimeRange {
+            start: 0.0,
+            end: None,
+        });
+        self.segments.retain_mut(|segment| {
+            let Some(first) = segment.first() else {
+                return true;
+            };
+            let start = first.start as f64 / 100.;
+            if range.contains(start) {
+                return false;
+            }
+            if start < range.start {
+                segment.retain(|token| token.end as f64 / 100. <= range.start);
+                return !segment.is_empty();
+            }
+            true
+        });
+        self.segments.extend(other.segments);
+        self.segments
+            .sort_by_key(|segment| segment.first().map_or(0, |token| token.start));
+
+        self.range = self.range.zip(other.range).map(|(a, b)| TimeRange {
+            start: a.start.min(b.start),
+            end: a.end.zip(b.end).map(|(a, b)| a.max(b)),
+        });
+    }
+}
+
+/// Describes how a transcription was made.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Version of whisper-aligner which made the transcription.
+    pub tool_version: String,
+    pub model: ModelMetadata,
+    pub audio: AudioMetadata,
+    pub decoding: DecodingOptions,
+    /// Only present if the audio was split with silero VAD.
+    pub vad: Option<VadMetadata>,
+    /// Hash of the reference text whisper was prompted with, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference_sha256: Option<String>,
+    /// Set if the transcription was interrupted, audio after this many seconds may be missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interrupted_at: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelMetadata {
+    pub path: PathBuf,
+    pub sha256: String,
+    /// The model type as reported by whisper.cpp, e.g. "base" or "large".
+    pub model_type: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioMetadata {
+    pub name: String,
+    /// Index of the transcribed stream among all streams of the file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_index: Option<usize>,
+    /// Duration of the decoded audio in seconds, only that of the transcribed range if there is one.
+    pub duration: f64,
+    /// Hash of the audio file as it was read, before decoding. When the audio was read from a stream
+    /// which can't seek, it only covers the part that was read if the transcription was interrupted.
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    Greedy,
+    BeamSearch,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace('-', "_").as_str() {
+            "greedy" => Ok(Self::Greedy),
+            "beam_search" => Ok(Self::BeamSearch),
+            _ => Err(format!(
+                "unknown decoding strategy {s:?}, expected greedy or beam-search"
+            )),
+        }
+    }
+}
+
+/// Parameters controlling how whisper decodes the audio, see `whisper_full_params` in whisper.h
+/// for details.
+///
+/// Missing fields take their default value when deserializing, so presets only need to contain
+/// what they change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DecodingOptions {
+    pub strategy: Strategy,
+    /// Number of candidates to pick from when sampling with a temperature above 0 using the
+    /// greedy strategy.
+    pub best_of: i32,
+    pub beam_size: i32,
+    pub patience: f32,
+    pub temperature: f32,
+    /// Step by which the temperature is increased when decoding fails the thresholds below, 0
+    /// disables the fallback.
+    pub temperature_inc: f32,
+    pub entropy_thold: f32,
+    pub logprob_thold: f32,
+    pub no_speech_thold: f32,
+    /// Maximum segment length in characters, 0 means no limit.
+    pub max_len: i32,
+    pub split_on_word: bool,
+    /// Timestamp token probability threshold.
+    pub thold_pt: f32,
+    pub max_initial_ts: f32,
+    /// 0 uses every available thread, the metadata of a transcription holds the actual count.
+    pub threads: i32,
+    /// Additionally compute token timestamps using DTW, see [`Token::dtw`].
+    pub dtw: bool,
+    /// Sections of audio longer than this are cut before being passed to whisper.
+    pub max_section_seconds: f32,
+    /// Constrain decoding with a grammar matching the words of the reference expected in each
+    /// section, requires a reference.
+    pub reference_grammar: bool,
+    /// How much the logits of tokens not matching the grammar are lowered.
+    pub grammar_penalty: f32,
+    /// Added to the logits of tokens making up words of the reference expected in each section, 0
+    /// disables boosting. Requires a reference.
+    pub vocabulary_bonus: f32,
+}
+
+impl Default for DecodingOptions {
+    fn default() -> Self {
+        DecodingOptions {
+            strategy: Strategy::BeamSearch,
+            best_of: 5,
+            beam_size: 5,
+            patience: -1.0,
+            temperature: 0.0,
+            temperature_inc: 0.2,
+            entropy_thold: 2.4,
+            logprob_thold: -1.0,
+            no_speech_thold: 0.6,
+            max_len: 0,
+            split_on_word: false,
+            thold_pt: 0.01,
+            max_initial_ts: 1.0,
+            threads: 0,
+            dtw: false,
+            max_section_seconds: 600.0,
+            reference_grammar: false,
+            grammar_penalty: 10.0,
+            vocabulary_bonus: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VadMetadata {
+    pub model: PathBuf,
+    pub threshold: f32,
+    pub min_silence_seconds: f32,
+    pub min_trim_silence_seconds: f32,
+    pub speech_padding_seconds: f32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(tokens: &[(i64, i64, &str)]) -> Vec<Token> {
+        tokens
+            .iter()
+            .map(|&(start, end, text)| Token {
+                probability: 1.0,
+                start,
+                end,
+                text: text.to_string(),
+                dtw: None,
+            })
+            .collect()
+    }
+
+    fn transcription(range: Option<TimeRange>, segments: Vec<Vec<Token>>) -> Transcription {
+        Transcription {
+            version: TRANSCRIPTION_VERSION,
+            language: "en".to_string(),
+            language_probability: None,
+            metadata: None,
+            range,
+            segments,
+        }
+    }
+
+    #[test]
+    fn merge_straddling_segment() {
+        let mut main = transcription(
+            None,
+            vec![
+                segment(&[(0, 100, " one"), (100, 200, " two")]),
+                segment(&[
+                    (400, 480, " three"),
+                    (480, 520, " four"),
+                    (520, 600, " five"),
+                ]),
+                segment(&[(700, 800, " six")]),
+                segment(&[(1200, 1300, " seven")]),
+            ],
+        );
+        let part = transcription(
+            Some(TimeRange {
+                start: 5.0,
+                end: Some(10.0),
+            }),
+            vec![segment(&[(500, 600, " FOUR"), (600, 650, " FIVE")])],
+        );
+        main.merge(part);
+
+        let texts: Vec<Vec<&str>> = main
+            .segments
+            .iter()
+            .map(|segment| segment.iter().map(|token| token.text.as_str()).collect())
+            .collect();
+        assert_eq!(
+            texts,
+            [
+                vec![" one", " two"],
+                vec![" three"],
+                vec![" FOUR", " FIVE"],
+                vec![" seven"],
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_drops_segment_emptied_at_boundary() {
+        let mut main = transcription(
+            None,
+            vec![
+                segment(&[(450, 550, " one")]),
+                segment(&[(800, 900, " two")]),
+            ],
+        );
+        let part = transcription(
+            Some(TimeRange {
+                start: 5.0,
+                end: None,
+            }),
+            vec![segment(&[(500, 600, " ONE")])],
+        );
+        main.merge(part);
+
+        assert_eq!(main.segments.len(), 1);
+        assert_eq!(main.segments[0][0].text, " ONE");
+    }
+}
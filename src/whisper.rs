@@ -1,13 +1,30 @@
 use std::{
     borrow::Cow,
+    cell::RefCell,
     ffi::{c_void, CStr, CString},
-    io::Read,
-    path::PathBuf,
+    io::{Read, Seek},
+    ops::Range,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
-use serde::{Deserialize, Serialize};
-
-use crate::{ff, silero};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    boost::VocabularyBoost,
+    checkpoint::{Checkpoint, CheckpointOptions, Settings},
+    error::{Context, Error, Result},
+    ff,
+    grammar::ReferenceGrammar,
+    progress::Progress,
+    prompt::ReferencePrompts,
+    sections::{SampleBuffer, SectionSplitter, CHUNK_SAMPLES},
+    transcription::{
+        AudioMetadata, DecodingOptions, Metadata, ModelMetadata, Strategy, TimeRange, Token,
+        Transcription, VadMetadata, TRANSCRIPTION_VERSION,
+    },
+};
 
 fn hsl_to_rgb(h: f32, s: f32, l: f32) -> (u8, u8, u8) {
     if s == 0.0 {
@@ -48,18 +65,10 @@ fn hsl_to_rgb(h: f32, s: f32, l: f32) -> (u8, u8, u8) {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Token {
-    pub probability: f32,
-    pub start: i64,
-    pub end: i64,
-    pub text: String,
-}
-
 impl Token {
-    fn write_colored(&self, mut out: impl std::io::Write) -> std::io::Result<()> {
+    fn colored(&self) -> String {
         let (r, g, b) = hsl_to_rgb(self.probability * (100.0 / 360.0), 1.0, 0.5);
-        write!(out, "\x1b[38;2;{r};{g};{b}m{}\x1b[0m", self.text)
+        format!("\x1b[38;2;{r};{g};{b}m{}\x1b[0m", self.text)
     }
 }
 
@@ -83,6 +92,7 @@ unsafe fn fixup_whisper_tokens<'a>(
     let mut current_broken_text = vec![];
     let mut current_broken_startts = 0;
     let mut current_broken_endts = 0;
+    let mut current_broken_dtw = -1;
     let mut current_broken_vlen = 0.0;
 
     let mut result = vec![];
@@ -91,6 +101,7 @@ unsafe fn fixup_whisper_tokens<'a>(
             if current_broken_text.is_empty() {
                 current_broken_startts = token.data.t0;
                 current_broken_endts = token.data.t1;
+                current_broken_dtw = token.data.t_dtw;
             }
             current_broken_text.extend_from_slice(token.text.to_bytes());
             current_broken_nmerge += 1;
@@ -105,7 +116,7 @@ unsafe fn fixup_whisper_tokens<'a>(
 
         if !current_broken_text.is_empty() && std::str::from_utf8(&current_broken_text).is_ok() {
             let text = String::from_utf8_unchecked(std::mem::take(&mut current_broken_text));
-            println!("merged {current_broken_nmerge} partial tokens into \"{text}\"");
+            log::info!("merged {current_broken_nmerge} partial tokens into \"{text}\"");
             result.push(SimplerToken {
                 data: whisper_cpp_sys::whisper_token_data {
                     id: -1,
@@ -116,7 +127,7 @@ unsafe fn fixup_whisper_tokens<'a>(
                     ptsum: -1.0,
                     t0: current_broken_startts,
                     t1: current_broken_endts,
-                    t_dtw: -1,
+                    t_dtw: current_broken_dtw,
                     vlen: current_broken_vlen,
                 },
                 text: Cow::Owned(text),
@@ -126,11 +137,12 @@ unsafe fn fixup_whisper_tokens<'a>(
             current_broken_nmerge = 0;
             current_broken_startts = 0;
             current_broken_endts = 0;
+            current_broken_dtw = -1;
         }
     }
 
     if !current_broken_text.is_empty() {
-        println!(
+        log::info!(
             "warning partial token left over in segment ({} bytes)",
             current_broken_text.len()
         )
@@ -139,10 +151,79 @@ unsafe fn fixup_whisper_tokens<'a>(
     result
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Transcription {
-    pub language: String,
-    pub segments: Vec<Vec<Token>>,
+/// The part of the ggml model header needed to pick the DTW alignment heads.
+struct ModelHparams {
+    n_vocab: i32,
+    n_audio_layer: i32,
+    n_text_layer: i32,
+    n_mels: i32,
+}
+
+fn read_model_hparams(model: &Path) -> Result<ModelHparams> {
+    let mut header = [0u8; 12 * 4];
+    std::fs::File::open(model)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .with_context(|| format!("failed to read whisper model header {}", model.display()))?;
+    let field = |i: usize| i32::from_le_bytes(header[i * 4..i * 4 + 4].try_into().unwrap());
+
+    if field(0) as u32 != 0x67676d6c {
+        return Err(Error::Invalid(format!(
+            "{} is not a ggml whisper model",
+            model.display()
+        )));
+    }
+
+    Ok(ModelHparams {
+        n_vocab: field(1),
+        n_audio_layer: field(5),
+        n_text_layer: field(9),
+        n_mels: field(10),
+    })
+}
+
+/// Picks the alignment heads preset matching the model, whisper.cpp can't figure it out on its own.
+fn dtw_aheads_preset(model: &Path) -> Result<whisper_cpp_sys::whisper_alignment_heads_preset> {
+    use whisper_cpp_sys::*;
+
+    let hparams = read_model_hparams(model)?;
+    let english_only = hparams.n_vocab == 51864;
+    Ok(match (hparams.n_audio_layer, english_only) {
+        (4, true) => whisper_alignment_heads_preset_WHISPER_AHEADS_TINY_EN,
+        (4, false) => whisper_alignment_heads_preset_WHISPER_AHEADS_TINY,
+        (6, true) => whisper_alignment_heads_preset_WHISPER_AHEADS_BASE_EN,
+        (6, false) => whisper_alignment_heads_preset_WHISPER_AHEADS_BASE,
+        (12, true) => whisper_alignment_heads_preset_WHISPER_AHEADS_SMALL_EN,
+        (12, false) => whisper_alignment_heads_preset_WHISPER_AHEADS_SMALL,
+        (24, true) => whisper_alignment_heads_preset_WHISPER_AHEADS_MEDIUM_EN,
+        (24, false) => whisper_alignment_heads_preset_WHISPER_AHEADS_MEDIUM,
+        (32, false)
+            if hparams.n_mels == 128 && hparams.n_vocab == 51866 && hparams.n_text_layer == 32 =>
+        {
+            whisper_alignment_heads_preset_WHISPER_AHEADS_LARGE_V3
+        }
+        // The headers of large-v1 and large-v2 are the same, so only the file name is left to tell
+        // them apart.
+        (32, false) if hparams.n_mels == 80 && hparams.n_vocab == 51865 => {
+            let name = model
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let v1 = name.contains("v1");
+            log::warn!(
+                "large-v1 and large-v2 models can't be told apart, using the DTW alignment heads of {} because the model is named {name:?}.",
+                if v1 { "large-v1" } else { "large-v2" }
+            );
+            if v1 {
+                whisper_alignment_heads_preset_WHISPER_AHEADS_LARGE_V1
+            } else {
+                whisper_alignment_heads_preset_WHISPER_AHEADS_LARGE_V2
+            }
+        }
+        _ => {
+            log::warn!("No DTW alignment heads preset matches this model, using the heads of the top text layers instead.");
+            whisper_alignment_heads_preset_WHISPER_AHEADS_N_TOP_MOST
+        }
+    })
 }
 
 #[derive(Debug, Clone)]
@@ -154,129 +235,372 @@ pub struct SileroOptions {
     pub speech_padding_seconds: f32,
 }
 
-pub fn transcribe(
-    file: impl Read,
-    language: String,
-    model: PathBuf,
-    silero: Option<SileroOptions>,
-) -> Transcription {
-    let mut samples = vec![];
-
-    unsafe {
-        let frames = ff::audio_demux_transcode_16khz_pcmf32le(ff::read_to_avio(Box::new(file)))
-            .unwrap()
-            .map(|x| &*x.unwrap());
-        for frame in frames {
-            assert!(frame.format == ffmpeg::AVSampleFormat::AV_SAMPLE_FMT_FLT as i32);
-            samples.extend_from_slice(std::slice::from_raw_parts(
-                frame.data[0] as *const f32,
-                frame.nb_samples as usize,
-            ));
-        }
+/// Everything that controls how [`transcribe`] runs.
+#[derive(Debug, Clone)]
+pub struct TranscribeOptions {
+    /// Detected from the audio if not set.
+    pub language: Option<String>,
+    pub model: PathBuf,
+    /// Split the audio into sections of speech with silero VAD.
+    pub silero: Option<SileroOptions>,
+    pub decoding: DecodingOptions,
+    /// Text expected to be spoken in the audio, the part of it estimated to be spoken in each
+    /// section is passed to whisper as its prompt to steer it towards the same names and spellings.
+    pub reference: Option<String>,
+    /// The first audio stream is transcribed if not set.
+    pub audio_stream: Option<ff::StreamSelector>,
+    /// Only transcribe this part of the audio, the timestamps stay relative to the start of the
+    /// whole audio.
+    pub range: Option<TimeRange>,
+    pub checkpoint: Option<CheckpointOptions>,
+}
+
+/// A loaded whisper model, freed when dropped.
+struct WhisperContext(*mut whisper_cpp_sys::whisper_context);
+
+impl Drop for WhisperContext {
+    fn drop(&mut self) {
+        unsafe { whisper_cpp_sys::whisper_free(self.0) }
     }
+}
 
-    let speech_sections = if let Some(SileroOptions {
-        path,
-        threshold,
-        min_silence_seconds,
-        min_trim_silence_seconds,
-        speech_padding_seconds,
-    }) = silero
-    {
-        const HALF_A_SECOND_SAMPLES: usize = 8000;
-        assert!(min_silence_seconds > 0.5);
-        let min_silence_chunks = (min_silence_seconds * (16000. / 480.)) as usize;
-        let min_trim_silence_chunks = (min_trim_silence_seconds * (16000. / 480.)) as usize;
-        let padding_samples = (speech_padding_seconds * 16000.) as usize;
-        if padding_samples / 480 > std::cmp::min(min_silence_chunks, min_trim_silence_chunks) {
-            panic!("Speech padding is too large")
-        }
-        let trim_padding_samples = padding_samples;
-
-        let mut silero = silero::Silero::new(silero::SampleRate::Hz16000, path).unwrap();
-        let mut silence_chain_length = 0;
-        let mut speech_sections = vec![];
-        let mut current_start = 0;
-        let mut is_at_start = true;
-        for (i, chunk) in samples.chunks(480).enumerate() {
-            let mut padded;
-            let chunk = if chunk.len() < 480 {
-                padded = Vec::with_capacity(480);
-                padded.extend_from_slice(chunk);
-                padded.resize(480, 0.0);
-                padded.as_slice()
-            } else {
-                chunk
-            };
-            let speech_probability = silero.run(chunk).unwrap();
+/// Passes everything read through to a hasher shared with whoever created it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Rc<RefCell<Sha256>>,
+}
 
-            println!(
-                "silero: {i} chunk {:.2}s = {:.2}",
-                (i * 480) as f64 / 16000.0,
-                speech_probability
-            );
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.borrow_mut().update(&buf[..read]);
+        Ok(read)
+    }
+}
 
-            if speech_probability <= threshold {
-                silence_chain_length += 1;
-                if is_at_start {
-                    current_start = i * 480;
-                }
-            } else {
-                if is_at_start {
-                    current_start = current_start.saturating_sub(trim_padding_samples);
-                    is_at_start = false;
-                } else if silence_chain_length >= min_silence_chunks {
-                    speech_sections.push((
-                        current_start,
-                        (i - silence_chain_length) * 480 + padding_samples,
-                    ));
-                    current_start = i * 480 - padding_samples;
-                }
-                silence_chain_length = 0;
-            }
-        }
+/// Number of 30 second windows of the first section the language is detected on, the detected
+/// probabilities are averaged.
+const LANGUAGE_DETECTION_WINDOWS: usize = 3;
+
+/// Detects the spoken language of `samples`, returning it with its probability.
+unsafe fn detect_language(
+    ctx: *mut whisper_cpp_sys::whisper_context,
+    samples: &[f32],
+    threads: i32,
+) -> Result<(CString, f32)> {
+    use whisper_cpp_sys::*;
+
+    let code = whisper_pcm_to_mel(ctx, samples.as_ptr(), samples.len() as i32, threads);
+    if code != 0 {
+        return Err(Error::Whisper {
+            context: "failed to compute the spectrogram for language detection".to_string(),
+            code,
+        });
+    }
 
-        if silence_chain_length < min_trim_silence_chunks {
-            silence_chain_length = 0;
+    let languages = whisper_lang_max_id() as usize + 1;
+    let mut probabilities = vec![0.0; languages];
+    let mut window_probabilities = vec![0.0; languages];
+    let seconds = samples.len() / 16000;
+    let windows = (seconds / 30).clamp(1, LANGUAGE_DETECTION_WINDOWS);
+    for i in 0..windows {
+        let offset_ms = seconds.saturating_sub(30) * 1000 * i / (windows - 1).max(1);
+        let code = whisper_lang_auto_detect(
+            ctx,
+            offset_ms as i32,
+            threads,
+            window_probabilities.as_mut_ptr(),
+        );
+        if code < 0 {
+            return Err(Error::Whisper {
+                context: "failed to detect the language".to_string(),
+                code,
+            });
+        }
+        for (total, probability) in probabilities.iter_mut().zip(&window_probabilities) {
+            *total += probability / windows as f32;
         }
+    }
 
-        speech_sections.push((
-            current_start,
-            (samples.len() - silence_chain_length * 480)
-                .saturating_add(trim_padding_samples)
-                .clamp(0, samples.len()),
-        ));
+    let mut ranked = (0..languages).collect::<Vec<_>>();
+    ranked.sort_by(|a, b| probabilities[*b].total_cmp(&probabilities[*a]));
+    let language = |id: usize| CStr::from_ptr(whisper_lang_str(id as i32));
+    log::info!(
+        "Detected languages: {}",
+        ranked
+            .iter()
+            .take(5)
+            .map(|&id| format!(
+                "{} {:.1}%",
+                language(id).to_string_lossy(),
+                probabilities[id] * 100.
+            ))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    Ok((language(ranked[0]).to_owned(), probabilities[ranked[0]]))
+}
 
-        speech_sections
-    } else {
-        vec![(0, samples.len())]
+/// Tokenizes `text` with the vocabulary of the model, keeping at most `max_tokens` from its start.
+pub(crate) unsafe fn tokenize(
+    ctx: *mut whisper_cpp_sys::whisper_context,
+    text: &str,
+    max_tokens: usize,
+) -> Result<Vec<whisper_cpp_sys::whisper_token>> {
+    let text = CString::new(text.replace('\0', "")).unwrap();
+    // Every token is at least one byte long.
+    let mut tokens = vec![0; text.as_bytes().len()];
+    let count = whisper_cpp_sys::whisper_tokenize(
+        ctx,
+        text.as_ptr(),
+        tokens.as_mut_ptr(),
+        tokens.len() as i32,
+    );
+    if count < 0 {
+        return Err(Error::Whisper {
+            context: "failed to tokenize prompt".to_string(),
+            code: count,
+        });
+    }
+    tokens.truncate(std::cmp::min(count as usize, max_tokens));
+    Ok(tokens)
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    log::info!("Hashing {}", path.display());
+    let mut hasher = Sha256::new();
+    std::fs::File::open(path)
+        .and_then(|mut file| std::io::copy(&mut file, &mut hasher))
+        .with_context(|| format!("failed to hash {}", path.display()))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Lists the audio streams of `file`, to be picked with a [`crate::StreamSelector`].
+pub fn list_audio_streams(file: impl Read + Seek) -> Result<Vec<ff::AudioStreamInfo>> {
+    unsafe { ff::list_audio_streams(ff::read_seek_to_avio(Box::new(file))) }
+        .context("failed to read audio streams")
+}
+
+/// Transcribes the audio in `file`, `audio_name` is only used to describe it in the metadata.
+///
+/// `file` is only read sequentially, which isn't enough for some containers, use
+/// [`transcribe_file`] for anything that can seek. Setting `cancel` stops the transcription as soon
+/// as possible, what was transcribed up to that point is still returned with
+/// [`Metadata::interrupted_at`] set.
+pub fn transcribe(
+    file: impl Read,
+    audio_name: String,
+    options: TranscribeOptions,
+    cancel: Option<&AtomicBool>,
+) -> Result<Transcription> {
+    let hasher = Rc::new(RefCell::new(Sha256::new()));
+    let reader = HashingReader {
+        inner: file,
+        hasher: hasher.clone(),
     };
+    transcribe_avio(
+        move || unsafe { ff::read_to_avio(Box::new(reader)) },
+        None,
+        move || format!("{:x}", hasher.take().finalize()),
+        audio_name,
+        options,
+        cancel,
+    )
+}
+
+/// Like [`transcribe`], but lets ffmpeg seek in `file`.
+///
+/// The file is hashed separately before being decoded, since seeking means it isn't read in order.
+pub fn transcribe_file(
+    mut file: impl Read + Seek,
+    audio_name: String,
+    options: TranscribeOptions,
+    cancel: Option<&AtomicBool>,
+) -> Result<Transcription> {
+    log::info!("Hashing {audio_name}");
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)
+        .and_then(|_| file.rewind())
+        .with_context(|| format!("failed to hash {audio_name}"))?;
+    let sha256 = format!("{:x}", hasher.finalize());
+
+    transcribe_avio(
+        move || unsafe { ff::read_seek_to_avio(Box::new(file)) },
+        Some(sha256.clone()),
+        move || sha256,
+        audio_name,
+        options,
+        cancel,
+    )
+}
 
+/// `open` creates the context ffmpeg reads the audio from, `audio_sha256` is called once decoding
+/// is done. `known_sha256` is the hash of the audio if it's already known before decoding, only then
+/// can it be checked when resuming from a checkpoint.
+fn transcribe_avio(
+    open: impl FnOnce() -> *mut ffmpeg::AVIOContext,
+    known_sha256: Option<String>,
+    audio_sha256: impl FnOnce() -> String,
+    audio_name: String,
+    TranscribeOptions {
+        language,
+        model,
+        silero,
+        mut decoding,
+        reference,
+        audio_stream,
+        range,
+        checkpoint,
+    }: TranscribeOptions,
+    cancel: Option<&AtomicBool>,
+) -> Result<Transcription> {
+    let cancelled = || cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed));
+    let vad_metadata = silero.as_ref().map(|silero| VadMetadata {
+        model: silero.path.clone(),
+        threshold: silero.threshold,
+        min_silence_seconds: silero.min_silence_seconds,
+        min_trim_silence_seconds: silero.min_trim_silence_seconds,
+        speech_padding_seconds: silero.speech_padding_seconds,
+    });
+    if decoding.max_section_seconds < 1.0 {
+        return Err(Error::Invalid(
+            "maximum section duration has to be at least 1s".to_string(),
+        ));
+    }
+    if decoding.beam_size < 1 || decoding.best_of < 1 {
+        return Err(Error::Invalid(
+            "beam size and best of have to be at least 1".to_string(),
+        ));
+    }
+    if decoding.reference_grammar && reference.is_none() {
+        return Err(Error::Invalid(
+            "a reference is needed to constrain decoding with a grammar".to_string(),
+        ));
+    }
+    if decoding.vocabulary_bonus != 0.0 && reference.is_none() {
+        return Err(Error::Invalid(
+            "a reference is needed to boost its vocabulary".to_string(),
+        ));
+    }
+    if let Some(range) = range {
+        if range.start < 0.0 || range.end.is_some_and(|end| end <= range.start) {
+            return Err(Error::Invalid(format!("invalid time range {range}")));
+        }
+    }
+    // Position of the first decoded sample in the whole audio, in seconds.
+    let offset = range.map_or(0.0, |range| range.start);
+    if decoding.threads <= 0 {
+        decoding.threads = std::thread::available_parallelism()
+            .map(usize::from)
+            .unwrap_or(1) as i32;
+    }
+    let mut splitter = SectionSplitter::new(silero, decoding.max_section_seconds)?;
     let mut segments: Vec<Vec<Token>> = vec![];
+    let model_sha256 = sha256_file(&model)?;
+    let reference_sha256 = reference
+        .as_ref()
+        .map(|reference| format!("{:x}", Sha256::digest(reference)));
 
-    unsafe {
+    let stream_index;
+    let (model_type, decoding, samples, interrupted_at, language, language_probability) = unsafe {
         use whisper_cpp_sys::*;
-        let cparams = whisper_context_default_params();
+        let mut cparams = whisper_context_default_params();
+        if decoding.dtw {
+            cparams.dtw_token_timestamps = true;
+            cparams.dtw_aheads_preset = dtw_aheads_preset(&model)?;
+        }
 
-        let model_cstr = CString::new(model.to_str().unwrap()).unwrap();
+        let mut language = language
+            .map(|language| {
+                CString::new(language.clone())
+                    .ok()
+                    .filter(|language| whisper_lang_id(language.as_ptr()) >= 0)
+                    .ok_or_else(|| Error::Invalid(format!("unknown language {language:?}")))
+            })
+            .transpose()?;
+        // Only set if the language was detected.
+        let mut language_probability = None;
+
+        let model_cstr = model
+            .to_str()
+            .and_then(|path| CString::new(path).ok())
+            .ok_or_else(|| {
+                Error::Invalid(format!("invalid whisper model path {}", model.display()))
+            })?;
         let ctx = whisper_init_from_file_with_params(model_cstr.as_ptr(), cparams);
+        if ctx.is_null() {
+            return Err(Error::WhisperInit { model });
+        }
+        let ctx = WhisperContext(ctx);
+        let model_type = CStr::from_ptr(whisper_model_type_readable(ctx.0))
+            .to_string_lossy()
+            .into_owned();
+        if language.is_none() && whisper_is_multilingual(ctx.0) == 0 {
+            log::info!("The model is English-only, not detecting the language");
+            language = Some(CString::new("en").unwrap());
+        }
 
-        let mut wparams =
-            whisper_full_default_params(whisper_sampling_strategy_WHISPER_SAMPLING_BEAM_SEARCH);
+        let mut wparams = whisper_full_default_params(match decoding.strategy {
+            Strategy::Greedy => whisper_sampling_strategy_WHISPER_SAMPLING_GREEDY,
+            Strategy::BeamSearch => whisper_sampling_strategy_WHISPER_SAMPLING_BEAM_SEARCH,
+        });
 
-        let language = CString::new(language.clone()).unwrap();
-        wparams.language = language.as_ptr();
         wparams.translate = false;
-        wparams.n_threads = std::thread::available_parallelism()
-            .map(usize::from)
-            .unwrap_or(1) as i32;
-        wparams.token_timestamps = true; // TODO: Figure out this whole "DTW whisper" thing
+        wparams.n_threads = decoding.threads;
+        wparams.token_timestamps = true;
         wparams.no_timestamps = false;
-        wparams.beam_search.beam_size = 5;
+        wparams.thold_pt = decoding.thold_pt;
+        wparams.max_len = decoding.max_len;
+        wparams.split_on_word = decoding.split_on_word;
+        wparams.max_initial_ts = decoding.max_initial_ts;
+        wparams.temperature = decoding.temperature;
+        wparams.temperature_inc = decoding.temperature_inc;
+        wparams.entropy_thold = decoding.entropy_thold;
+        wparams.logprob_thold = decoding.logprob_thold;
+        wparams.no_speech_thold = decoding.no_speech_thold;
+        wparams.greedy.best_of = decoding.best_of;
+        wparams.beam_search.beam_size = decoding.beam_size;
+        wparams.beam_search.patience = decoding.patience;
+        wparams.grammar_penalty = decoding.grammar_penalty;
+
+        let mut checkpoint = checkpoint
+            .map(|options| {
+                Checkpoint::open(
+                    &options,
+                    Settings {
+                        // A detected language is detected again when resuming.
+                        language: language.as_ref().map_or_else(
+                            || "auto".to_string(),
+                            |language| language.to_string_lossy().into_owned(),
+                        ),
+                        model_sha256: model_sha256.clone(),
+                        // The number of threads doesn't change the output of whisper.
+                        decoding: DecodingOptions {
+                            threads: 0,
+                            ..decoding.clone()
+                        },
+                        vad: vad_metadata.clone(),
+                        reference_sha256: reference_sha256.clone(),
+                        audio_name: audio_name.clone(),
+                        audio_sha256: known_sha256,
+                        audio_stream: audio_stream.clone(),
+                        range,
+                    },
+                )
+            })
+            .transpose()?;
 
         struct User {
             time_offset: i64,
             segments: *mut Vec<Vec<Token>>,
+            checkpoint: Option<*mut Checkpoint>,
+            /// The first error that happened inside the callback.
+            error: Option<Error>,
+            boost: Option<*const VocabularyBoost>,
+            /// Number of text tokens whisper chose and how many of them were boosted.
+            text_tokens: usize,
+            boosted_tokens: usize,
         }
 
         unsafe extern "C" fn on_new_segment(
@@ -288,6 +612,16 @@ pub fn transcribe(
             let user = &mut *(user as *mut User);
             let total = whisper_full_n_segments(ctx);
             for i in (total - n_new)..total {
+                if let Some(boost) = user.boost {
+                    for j in 0..whisper_full_n_tokens(ctx, i) {
+                        let id = whisper_full_get_token_id(ctx, i, j);
+                        if id < whisper_token_eot(ctx) {
+                            user.text_tokens += 1;
+                            user.boosted_tokens += (*boost).contains(id) as usize;
+                        }
+                    }
+                }
+
                 let fixed =
                     fixup_whisper_tokens((0..whisper_full_n_tokens(ctx, i)).map(|j| RawToken {
                         index: Some(j),
@@ -296,6 +630,7 @@ pub fn transcribe(
                     }));
 
                 let mut out = vec![];
+                let mut colored = String::new();
 
                 for SimplerToken { data, text } in fixed {
                     if text.starts_with("[_") && text.ends_with("]") {
@@ -307,36 +642,322 @@ pub fn transcribe(
                         end: user.time_offset + data.t1,
                         probability: data.p,
                         text: text.to_string(),
+                        dtw: (data.t_dtw >= 0).then(|| user.time_offset + data.t_dtw),
                     };
-                    basic.write_colored(&mut std::io::stdout()).unwrap();
+                    colored += &basic.colored();
                     out.push(basic);
                 }
 
+                // Segments of only special tokens have nothing to align.
+                if out.is_empty() {
+                    continue;
+                }
+                if let (Some(checkpoint), None) = (user.checkpoint, user.error.as_ref()) {
+                    user.error = (*checkpoint).write_segment(&out).err();
+                }
                 (*user.segments).push(out);
-                println!()
+                log::info!("{colored}");
             }
         }
 
         wparams.new_segment_callback = Some(on_new_segment);
 
-        for (section_start, section_end) in speech_sections {
+        unsafe extern "C" fn on_progress(
+            _ctx: *mut whisper_context,
+            _whisper_state: *mut whisper_state,
+            progress: i32,
+            user: *mut c_void,
+        ) {
+            (*(user as *const RefCell<Progress>))
+                .borrow_mut()
+                .section_progress(progress);
+        }
+
+        unsafe extern "C" fn should_abort(user: *mut c_void) -> bool {
+            (*(user as *const AtomicBool)).load(Ordering::Relaxed)
+        }
+
+        if let Some(cancel) = cancel {
+            wparams.abort_callback = Some(should_abort);
+            wparams.abort_callback_user_data = cancel as *const AtomicBool as *mut c_void;
+        }
+
+        let ff::DecodedAudio {
+            duration,
+            stream,
+            frames,
+        } = ff::audio_demux_transcode_16khz_pcmf32le(
+            open(),
+            audio_stream.as_ref(),
+            offset,
+            range.and_then(|range| range.end),
+        )
+        .with_context(|| match audio_stream.as_ref() {
+            Some(selector) => format!("failed to open audio stream {selector} for decoding"),
+            None => "failed to open audio for decoding".to_string(),
+        })?;
+        log::info!("Transcribing audio stream {stream}");
+        if let Some(range) = range {
+            log::info!("Transcribing only {range}");
+        }
+        // Duration of the part which is decoded.
+        let decoded_duration = duration.map(|duration| {
+            let end = range
+                .and_then(|range| range.end)
+                .map_or(duration, |end| end.min(duration));
+            (end - offset).max(0.0)
+        });
+        stream_index = Some(stream.stream_index);
+        let progress = RefCell::new(Progress::new(decoded_duration));
+        let mut prompts = reference.map(|reference| ReferencePrompts::new(reference, duration));
+        let max_prompt_tokens = (whisper_n_text_ctx(ctx.0) / 2) as usize;
+        // Boosted and total text tokens chosen by whisper over all sections.
+        let mut boost_stats = (0, 0);
+        wparams.progress_callback = Some(on_progress);
+        wparams.progress_callback_user_data = &progress as *const RefCell<Progress> as *mut c_void;
+
+        // Returns whether the section was finished, it isn't if whisper was cancelled.
+        let mut transcribe_section = |range: Range<usize>, section: &[f32]| -> Result<bool> {
+            let (section_start, section_end) = (range.start, range.end);
+            // Positions of the section in the whole audio.
+            let (start_seconds, end_seconds) = (
+                offset + section_start as f64 / 16000.,
+                offset + section_end as f64 / 16000.,
+            );
+            if language.is_none() {
+                log::info!("Detecting the language in {start_seconds:.2}s-{end_seconds:.2}s");
+                let (detected, probability) = detect_language(ctx.0, section, decoding.threads)?;
+                language = Some(detected);
+                language_probability = Some(probability);
+            }
+            wparams.language = language.as_ref().unwrap().as_ptr();
+            let first_segment = segments.len();
+            let update_prompts = |prompts: &mut Option<ReferencePrompts>,
+                                  segments: &[Vec<Token>]| {
+                if let Some(prompts) = prompts.as_mut() {
+                    let transcript = segments[first_segment..]
+                        .iter()
+                        .flatten()
+                        .map(|token| token.text.as_str())
+                        .collect::<String>();
+                    prompts.update(end_seconds, &transcript);
+                }
+            };
+
+            if let Some(finished) = checkpoint
+                .as_mut()
+                .and_then(|checkpoint| checkpoint.take_finished(&range))
+            {
+                log::info!(
+                    "Skipping segment {start_seconds:.2}s-{end_seconds:.2}s, it was transcribed before resuming"
+                );
+                segments.extend(finished);
+                update_prompts(&mut prompts, &segments);
+                return Ok(true);
+            }
+
+            let boost = prompts
+                .as_ref()
+                .filter(|_| decoding.vocabulary_bonus != 0.0)
+                .map(|prompts| {
+                    VocabularyBoost::new(
+                        ctx.0,
+                        prompts.expected(start_seconds, end_seconds),
+                        decoding.vocabulary_bonus,
+                    )
+                })
+                .transpose()?;
+            wparams.logits_filter_callback = None;
+            if let Some(boost) = boost.as_ref() {
+                log::info!("Boosting {} tokens of the reference", boost.len());
+                boost.apply(&mut wparams);
+            }
+
             let mut user = User {
                 // time_offset is tens of milliseconds
-                time_offset: (section_start as f64 / (16000. / 100.)) as i64,
+                time_offset: (start_seconds * 100.) as i64,
                 segments: &mut segments,
+                checkpoint: checkpoint.as_mut().map(|checkpoint| checkpoint as *mut _),
+                error: None,
+                boost: boost.as_ref().map(|boost| boost as *const _),
+                text_tokens: 0,
+                boosted_tokens: 0,
             };
 
             wparams.new_segment_callback_user_data = &mut user as *mut User as *mut c_void;
 
-            println!(
-                "Processing segment {:.2}s-{:.2}s with whisper",
-                section_start as f64 / 16000.,
-                section_end as f64 / 16000.
+            let prompt_tokens = prompts
+                .as_ref()
+                .map(|prompts| {
+                    let prompt = prompts.prompt(start_seconds);
+                    log::info!(
+                        "Prompting with the reference from {:?}",
+                        prompt.chars().take(40).collect::<String>()
+                    );
+                    tokenize(ctx.0, prompt, max_prompt_tokens)
+                })
+                .transpose()?;
+            if let Some(tokens) = prompt_tokens.as_ref() {
+                wparams.prompt_tokens = tokens.as_ptr();
+                wparams.prompt_n_tokens = tokens.len() as i32;
+                // Otherwise whisper keeps the text of the previous section as context, which takes
+                // precedence over the prompt.
+                wparams.no_context = true;
+            }
+
+            let mut grammar = prompts
+                .as_ref()
+                .filter(|_| decoding.reference_grammar)
+                .and_then(|prompts| {
+                    ReferenceGrammar::new(prompts.expected(start_seconds, end_seconds))
+                });
+            wparams.grammar_rules = std::ptr::null_mut();
+            wparams.n_grammar_rules = 0;
+            if let Some(grammar) = grammar.as_mut() {
+                log::info!(
+                    "Constraining whisper to {} words of the reference",
+                    grammar.word_count()
+                );
+                grammar.apply(&mut wparams);
+            }
+
+            log::info!("Processing segment {start_seconds:.2}s-{end_seconds:.2}s with whisper");
+            progress
+                .borrow_mut()
+                .section_started(section_start as f64 / 16000., section_end as f64 / 16000.);
+            let code = whisper_full(ctx.0, wparams, section.as_ptr(), section.len() as i32);
+            if cancelled() {
+                return Ok(false);
+            }
+            if code != 0 {
+                return Err(Error::Whisper {
+                    context: format!("failed to transcribe {start_seconds:.2}s-{end_seconds:.2}s"),
+                    code,
+                });
+            }
+            if let Some(error) = user.error {
+                return Err(error);
+            }
+            update_prompts(&mut prompts, &segments);
+            if boost.is_some() {
+                log::info!(
+                    "{} of {} tokens whisper chose were boosted",
+                    user.boosted_tokens,
+                    user.text_tokens
+                );
+                boost_stats.0 += user.boosted_tokens;
+                boost_stats.1 += user.text_tokens;
+            }
+
+            if let Some(checkpoint) = checkpoint.as_mut() {
+                checkpoint.finish_section(&range)?;
+            }
+            progress
+                .borrow_mut()
+                .section_finished(section_end as f64 / 16000.);
+            Ok(true)
+        };
+
+        let mut buffer = SampleBuffer::default();
+        let mut next_chunk = 0;
+        // Everything before this position was transcribed.
+        let mut transcribed_until = 0;
+        let mut run_section = |section: Range<usize>, buffer: &SampleBuffer| -> Result<bool> {
+            let end = section.end;
+            let finished = transcribe_section(section.clone(), buffer.slice(section))?;
+            if finished {
+                transcribed_until = end;
+            }
+            Ok(finished)
+        };
+
+        'decode: for frame in frames {
+            if cancelled() {
+                break;
+            }
+            let frame = &*frame.context("failed to decode audio")?;
+            assert!(frame.format == ffmpeg::AVSampleFormat::AV_SAMPLE_FMT_FLT as i32);
+            buffer.extend_from_slice(std::slice::from_raw_parts(
+                frame.data[0] as *const f32,
+                frame.nb_samples as usize,
+            ));
+            progress.borrow_mut().decoded(buffer.end() as f64 / 16000.);
+
+            while next_chunk + CHUNK_SAMPLES <= buffer.end() {
+                let chunk = buffer.slice(next_chunk..next_chunk + CHUNK_SAMPLES);
+                if let Some(section) = splitter.push(next_chunk, chunk)? {
+                    if !run_section(section, &buffer)? {
+                        break 'decode;
+                    }
+                }
+                next_chunk += CHUNK_SAMPLES;
+                buffer.discard_before(splitter.needed_from());
+            }
+        }
+
+        if !cancelled() && next_chunk < buffer.end() {
+            let chunk = buffer.slice(next_chunk..buffer.end());
+            if let Some(section) = splitter.push(next_chunk, chunk)? {
+                run_section(section, &buffer)?;
+            }
+        }
+        if !cancelled() {
+            if let Some(section) = splitter.finish(buffer.end()) {
+                run_section(section, &buffer)?;
+            }
+        }
+        progress.borrow_mut().finish();
+        if boost_stats.1 > 0 {
+            log::info!(
+                "{} of {} tokens ({:.1}%) whisper chose were boosted reference tokens",
+                boost_stats.0,
+                boost_stats.1,
+                boost_stats.0 as f64 / boost_stats.1 as f64 * 100.
             );
-            let section = &samples[section_start..section_end];
-            whisper_full(ctx, wparams, section.as_ptr(), section.len() as i32);
         }
-    }
 
-    Transcription { language, segments }
+        let interrupted_at = cancelled().then_some(offset + transcribed_until as f64 / 16000.);
+        let language = match language {
+            Some(language) => language.to_string_lossy().into_owned(),
+            None => {
+                log::warn!("There was no speech to detect the language of.");
+                "und".to_string()
+            }
+        };
+        (
+            model_type,
+            decoding,
+            buffer.end(),
+            interrupted_at,
+            language,
+            language_probability,
+        )
+    };
+
+    let audio_sha256 = audio_sha256();
+    Ok(Transcription {
+        version: TRANSCRIPTION_VERSION,
+        language,
+        language_probability,
+        metadata: Some(Metadata {
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            model: ModelMetadata {
+                path: model,
+                sha256: model_sha256,
+                model_type,
+            },
+            audio: AudioMetadata {
+                name: audio_name,
+                stream_index,
+                duration: samples as f64 / 16000.,
+                sha256: audio_sha256,
+            },
+            decoding,
+            vad: vad_metadata,
+            reference_sha256,
+            interrupted_at,
+        }),
+        range,
+        segments,
+    })
 }